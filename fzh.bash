@@ -0,0 +1,128 @@
+#!/bin/bash
+
+if [[ $- == *i* ]]; then # Check the shell is interactive
+
+  ##  Setup  ##################################################################
+
+  # Find the binary
+  FZH_PATH=${FZH_PATH:-$(command -v fzh)}
+  if [[ -z "$FZH_PATH" ]]; then
+    echo "\`fzh\` binary is missing. Please add it to your path before sourcing fzh.bash"
+    return 1
+  fi
+
+  # Identifies this shell for the `session` search scope. It isn't exported so
+  # subshells start their own session.
+  FZH_SESSION_ID=$("$FZH_PATH" session_id)
+  __fzh_tty=$(tty)
+
+  ##  Bind hooks  #############################################################
+
+  # Bash has no `preexec`, so a `DEBUG` trap stands in for it. The trap fires
+  # before every simple command, including the ones run by `PROMPT_COMMAND`, so
+  # `__fzh_at_prompt` is used to only mark the first command typed after a
+  # prompt was shown. Pressing enter on an empty line never fires the trap for a
+  # user command, which keeps empty lines out of the index.
+  __fzh_at_prompt=""
+  __fzh_command_pending=""
+  __fzh_history_number=""
+
+  __fzh_debug_trap() {
+    [[ -n "$COMP_LINE" ]] && return # Completion functions
+    [[ -z "$__fzh_at_prompt" ]] && return
+    [[ "$BASH_COMMAND" == __fzh_prompt_command* ]] && return
+    # The `ctrl-r` widget runs while the line is still being edited
+    [[ "$BASH_COMMAND" == __fzh_widget* ]] && return
+
+    __fzh_at_prompt=""
+    __fzh_command_pending=1
+    # Microseconds, `$EPOCHREALTIME` needs Bash 5
+    __fzh_command_start=${EPOCHREALTIME/[.,]/}
+  }
+
+  # Keep any existing `DEBUG` trap, e.g. from bash-preexec, running first so
+  # it still sees `$?` and `$_` as they were. `trap -p` prints it quoted as
+  # `trap -- '<command>' DEBUG`.
+  __fzh_read_trap() { __fzh_current_debug_trap=$3; }
+  eval "__fzh_read_trap $(trap -p DEBUG)"
+  unset -f __fzh_read_trap
+
+  if [[ "$__fzh_current_debug_trap" != *__fzh_debug_trap* ]]; then # Sourced twice
+    __fzh_previous_debug_trap=$__fzh_current_debug_trap
+    trap 'eval "$__fzh_previous_debug_trap"; __fzh_debug_trap' DEBUG
+  fi
+  unset __fzh_current_debug_trap
+
+  # `PROMPT_COMMAND` runs just before the prompt is shown, which is just after
+  # the last command finished, so `$?` still holds its exit status. The command
+  # text is read back from history since `$BASH_COMMAND` only holds the first
+  # simple command of a pipeline.
+  __fzh_prompt_command() {
+    local exit_code=$?
+
+    # Split the history number from the command, e.g. "  123  git status"
+    local history_line history_number="" last_command=""
+    history_line=$(HISTTIMEFORMAT= builtin history 1)
+    if [[ $history_line =~ ^\ *([0-9]+)\*?\ +(.*)$ ]]; then
+      history_number=${BASH_REMATCH[1]}
+      last_command=${BASH_REMATCH[2]}
+    fi
+
+    # Commands bash didn't save, like ones starting with a space under
+    # `HISTCONTROL=ignorespace`, leave the previous entry at the end of history.
+    # Repeats dropped by `ignoredups` are skipped the same way.
+    if [[ -n "$__fzh_command_pending" && "$history_number" != "$__fzh_history_number" ]]; then
+      local duration_options=()
+      if [[ -n "$__fzh_command_start" ]]; then
+        duration_options=(--duration $(( (${EPOCHREALTIME/[.,]/} - __fzh_command_start) / 1000 )))
+      fi
+
+      if [ -n "$FZH_DEBUG" ]; then
+        echo "exit_code: $exit_code" >&2
+        echo "duration: ${duration_options[*]}" >&2
+        echo "command: $last_command" >&2
+      fi
+
+      "$FZH_PATH" add \
+        --hostname "$HOSTNAME" \
+        --username "$USER" \
+        --session "$FZH_SESSION_ID" \
+        --shell bash \
+        --tty "$__fzh_tty" \
+        --pid $$ \
+        "${duration_options[@]}" \
+        "$exit_code:$last_command"
+    fi
+
+    __fzh_command_pending=""
+    __fzh_command_start=""
+    __fzh_history_number=$history_number
+    __fzh_at_prompt=1
+
+    return $exit_code
+  }
+  if [[ "$PROMPT_COMMAND" != *__fzh_prompt_command* ]]; then
+    PROMPT_COMMAND="__fzh_prompt_command${PROMPT_COMMAND:+;$PROMPT_COMMAND}"
+  else
+    [ -n "$FZH_DEBUG" ] && echo "__fzh_prompt_command already in PROMPT_COMMAND, skipping"
+  fi
+
+  ##  Keybinds  ###############################################################
+
+  # `bind -x` runs the function with the current line in `READLINE_LINE`, and
+  # whatever is left in it afterwards replaces the line. Bash can't accept the
+  # line from inside a `bind -x` function, so the selection is left in place
+  # for enter to run.
+  __fzh_widget() {
+    local result
+    # Set `FZH_SEARCH_SCOPE` to start in another scope, e.g. "directory"
+    result=$("$FZH_PATH" search --scope "${FZH_SEARCH_SCOPE:-global}" --session "$FZH_SESSION_ID" "$__fzh_tty" "$READLINE_LINE" </dev/tty)
+
+    if [[ -n ${result//[[:space:]]/} ]]; then # strip whitespace and check length is >0
+      READLINE_LINE=$result
+      READLINE_POINT=${#READLINE_LINE}
+    fi
+  }
+
+  bind -x '"\C-r": __fzh_widget'
+fi
@@ -0,0 +1,69 @@
+if status is-interactive
+
+    ##  Setup  ##################################################################
+
+    # Find the binary
+    if not set -q FZH_PATH
+        set -g FZH_PATH (command -v fzh)
+    end
+    if test -z "$FZH_PATH"
+        echo "`fzh` binary is missing. Please add it to your path before sourcing fzh.fish"
+        return 1
+    end
+
+    # Identifies this shell for the `session` search scope. It isn't exported so
+    # subshells start their own session.
+    set -g FZH_SESSION_ID ($FZH_PATH session_id)
+    set -g __fzh_tty (tty)
+
+    ##  Bind hooks  #############################################################
+
+    # `fish_postexec` is emitted after every command with the command line as the
+    # first argument, `$status` still holds its exit status and `$CMD_DURATION`
+    # how long it took in milliseconds.
+    function __fzh_postexec --on-event fish_postexec
+        set -l exit_code $status
+        set -l duration_ms $CMD_DURATION
+
+        # Pressing enter on an empty line still emits `fish_postexec`
+        string trim -- $argv[1] | string length -q; or return
+
+        if set -q FZH_DEBUG
+            echo "exit_code: $exit_code" >&2
+            echo "duration: $duration_ms" >&2
+            echo "command: $argv[1]" >&2
+        end
+
+        $FZH_PATH add \
+            --hostname "$hostname" \
+            --username "$USER" \
+            --session "$FZH_SESSION_ID" \
+            --shell fish \
+            --tty "$__fzh_tty" \
+            --pid $fish_pid \
+            --duration "$duration_ms" \
+            "$exit_code:$argv[1]"
+    end
+
+    ##  Keybinds  ###############################################################
+
+    function __fzh_widget
+        # Set `FZH_SEARCH_SCOPE` to start in another scope, e.g. "directory"
+        set -l scope global
+        set -q FZH_SEARCH_SCOPE; and set scope $FZH_SEARCH_SCOPE
+
+        set -l result ($FZH_PATH search --scope $scope --session "$FZH_SESSION_ID" $__fzh_tty (commandline | string collect) </dev/tty | string collect)
+
+        if string trim -- "$result" | string length -q # strip whitespace and check length is >0
+            commandline --replace -- $result
+            commandline --function execute
+        else
+            commandline --function repaint
+        end
+    end
+
+    bind \cr __fzh_widget
+    if bind -M insert >/dev/null 2>&1 # vi mode
+        bind -M insert \cr __fzh_widget
+    end
+end
@@ -1,10 +1,8 @@
-// todo:
-// - score by times_selected and exit_code
-
 use console::{measure_text_width, Key, Term};
 use dialoguer::theme::Theme;
 use dialoguer::{theme::ColorfulTheme, theme::SimpleTheme, Select};
 use fuzzy_matcher::FuzzyMatcher;
+use std::collections::HashMap;
 use std::fs;
 use std::path::Path;
 use std::{fmt, io, ops::Rem};
@@ -12,12 +10,13 @@ use std::{fmt, io, ops::Rem};
 #[macro_use]
 extern crate tantivy;
 use home::home_dir;
-use tantivy::collector::{Count, TopDocs};
+use tantivy::collector::{Count, DocSetCollector, TopDocs};
 use tantivy::directory::MmapDirectory;
-use tantivy::query::{BooleanQuery, Occur, QueryParser, RegexQuery, TermQuery};
+use tantivy::query::{AllQuery, BooleanQuery, Occur, Query, RangeQuery, RegexQuery, TermQuery};
 use tantivy::{Directory, IndexSettings, IndexSortByField, Order};
-use tantivy::Index;
+use tantivy::{Index, IndexWriter, Searcher};
 use tantivy::ReloadPolicy;
+use tantivy::TantivyError;
 use tantivy::{schema::*, DocId, Score, SegmentReader};
 
 use regex::Regex;
@@ -35,26 +34,255 @@ const HELP: &str = concatdoc! {"
     Usage: ", env!("CARGO_BIN_NAME"), " <command> [<args>]
 
     Commands:
-        import <shell> [<path>]   # Index command history for a shell (path defaults to ~/.zsh_history)
+        import <source> [<path>]  # Index command history from zsh, bash, fish, atuin, histdb or mcfly (path defaults to its history file)
+        search [<options>] <tty> [<text>]
+                                  # Start the search client
+            --mode <mode>         #   fuzzy, prefix, substring, word or regex (defaults to fuzzy, ctrl-s switches)
+            --scope <scope>       #   global, directory, subtree, repo or session (defaults to global, ctrl-f switches)
+            --session <id>        #   The shell session to use for the session scope
+            --limit <n>           #   The most results to show (defaults to 0, no limit)
         init <shell>              # Prints the init script (source with `eval \"$(fzh init zsh)\"`)
+        session_id                # Prints a new shell session ID, used by the init scripts
+        export [<options>]        # Write all indexed command history to stdout or a file
+            --format <format>     #   jsonl, csv, zsh, bash or fish (defaults to jsonl)
+            --output <path>       #   Write to a file instead of stdout
         delete_index              # Remove all indexed command history
 
     Notes:
-        - Only Zsh is currently supported
-        - All persistent data is stored in ~/.fzh
+        - Zsh, Bash and Fish are supported
+        - Fish history is read from ~/.local/share/fish/fish_history by default
+        - All persistent data is stored in ~/.fzh, indexes from older versions are migrated on first use
+        - Ranking is configured in ~/.config/fzh/config.toml, or the file FZH_CONFIG points at
 
     For setup and full documentation, see: https://github.com/pheen/fzh
 "};
 
+// Imported history doesn't record exit codes (or, for most shells, durations or
+// directories), these mark the value as missing rather than pretending it was 0.
+const UNKNOWN_EXIT_CODE: u64 = u64::MAX;
+const UNKNOWN_DURATION: u64 = u64::MAX;
+// Never a real directory, the shells always report an absolute path
+const UNKNOWN_DIRECTORY: &str = "<unknown>";
+
+struct HistoryEntry {
+    directory: String,
+    command: String,
+    exit_code: u64,
+    /// Milliseconds since the unix epoch
+    timestamp: u64,
+    duration_ms: u64,
+    hostname: String,
+    username: String,
+    /// Created when the init script is sourced
+    session: String,
+    /// The shell's name, e.g. `zsh`
+    shell: String,
+    tty: String,
+    /// The shell's process ID, 0 when unknown
+    pid: u64,
+    /// Empty outside of a git work tree
+    git: GitContext,
+    /// Paths the command was given, only recorded by fish
+    paths: Vec<String>,
+}
+
+impl Default for HistoryEntry {
+    fn default() -> Self {
+        Self {
+            directory: UNKNOWN_DIRECTORY.to_string(),
+            command: "".to_string(),
+            exit_code: UNKNOWN_EXIT_CODE,
+            timestamp: 0,
+            duration_ms: UNKNOWN_DURATION,
+            hostname: "".to_string(),
+            username: "".to_string(),
+            session: "".to_string(),
+            shell: "".to_string(),
+            tty: "".to_string(),
+            pid: 0,
+            git: GitContext::default(),
+            paths: vec![],
+        }
+    }
+}
+
+impl HistoryEntry {
+    /// Reads an entry back from its indexed document. Documents written by
+    /// older versions of fzh keep the defaults for fields they don't have.
+    fn from_document(doc: &Document, schema: &Schema) -> HistoryEntry {
+        let defaults = HistoryEntry::default();
+        let text = |name: &str| schema.get_field(name).map(|field| stored_string(doc, field)).unwrap_or_default();
+        let number = |name: &str, default: u64| schema.get_field(name).map(|field| stored_u64(doc, field)).unwrap_or(default);
+
+        // Older imports stored an empty directory
+        let directory = match text("directory") {
+            directory if directory.is_empty() => defaults.directory,
+            directory => directory,
+        };
+
+        HistoryEntry {
+            directory,
+            command: text("command"),
+            exit_code: number("exit_code", defaults.exit_code),
+            timestamp: number("timestamp", defaults.timestamp),
+            duration_ms: number("duration_ms", defaults.duration_ms),
+            hostname: text("hostname"),
+            username: text("username"),
+            session: text("session"),
+            shell: text("shell"),
+            tty: text("tty"),
+            pid: number("pid", defaults.pid),
+            git: GitContext {
+                root: text("git_root"),
+                branch: text("git_branch"),
+                relative_path: text("git_path"),
+                repository: text("git_repository"),
+            },
+            paths: schema
+                .get_field("paths")
+                .map(|field| doc.get_all(field).filter_map(|value| value.as_text()).map(str::to_string).collect())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+lazy_static! {
+    static ref CONFIG: Config = load_config();
+}
+
+/// Settings from `~/.config/fzh/config.toml`, or the file `FZH_CONFIG` points
+/// at. Anything left out keeps its default.
+#[derive(Debug, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Config {
+    /// How long it takes a run to count for half as much
+    half_life_days: f64,
+    /// Failures that exited sooner than this, like typos, have the exit code
+    /// weight taken away
+    instant_failure_ms: u64,
+    weights: RankingWeights,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            half_life_days: 14.0,
+            instant_failure_ms: 50,
+            weights: RankingWeights::default(),
+        }
+    }
+}
+
+/// How much each signal adds to a command's score. Every signal is scaled to
+/// roughly 0-1 before it's weighted, 0 turns a signal off.
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RankingWeights {
+    /// How closely the command matches the search text
+    fuzzy: f32,
+    /// How recently it was last run, halving every half-life
+    recency: f32,
+    /// How often it's been run, with older runs decayed by the half-life
+    frequency: f32,
+    /// How often it's been picked from the search client
+    selection: f32,
+    /// Added for exit code 0, taken away for near-instant failures
+    exit_code: f32,
+    /// Run in the current directory, less for directories further away
+    directory: f32,
+    /// Run in the current git repository, wherever it's checked out
+    repo: f32,
+    /// Run in the current shell session
+    session: f32,
+}
+
+impl Default for RankingWeights {
+    fn default() -> Self {
+        Self {
+            fuzzy: 2.0,
+            recency: 1.0,
+            frequency: 1.0,
+            selection: 1.0,
+            exit_code: 1.0,
+            directory: 1.0,
+            repo: 1.0,
+            session: 0.5,
+        }
+    }
+}
+
+fn config_path() -> std::path::PathBuf {
+    match env::var("FZH_CONFIG") {
+        Ok(path) if !path.is_empty() => Path::new(&path).to_path_buf(),
+        _ => home_dir().unwrap().join(".config").join("fzh").join("config.toml"),
+    }
+}
+
+fn load_config() -> Config {
+    let path = config_path();
+
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(_) => return Config::default(),
+    };
+
+    match parse_config(&contents) {
+        Ok(config) => config,
+        Err(message) => {
+            eprintln!("Invalid config {:#?}: {}", path, message);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn parse_config(contents: &str) -> Result<Config, String> {
+    let config: Config = toml::from_str(contents).map_err(|error| error.to_string())?;
+
+    if config.half_life_days <= 0.0 {
+        return Err("half_life_days must be greater than 0".to_string());
+    }
+
+    Ok(config)
+}
+
+/// Returns the value given for `--<name> <value>` or `--<name>=<value>`.
+fn option_value(args: &[String], name: &str) -> Option<String> {
+    let flag = format!("--{}", name);
+    let flag_with_value = format!("{}=", flag);
+
+    for (i, arg) in args.iter().enumerate() {
+        if *arg == flag {
+            return args.get(i + 1).cloned();
+        }
+
+        if let Some(value) = arg.strip_prefix(&flag_with_value) {
+            return Some(value.to_string());
+        }
+    }
+
+    None
+}
+
+/// Counts the `--<name> <value>` or `--<name>=<value>` options at the start of
+/// `args`, everything after them is positional.
+fn leading_option_count(args: &[String]) -> usize {
+    let mut count = 0;
+
+    while count < args.len() && args[count].starts_with("--") {
+        count += if args[count].contains('=') { 1 } else { 2 };
+    }
+
+    count.min(args.len())
+}
+
 fn indexable_command(text: &str) -> bool {
     lazy_static! {
-        static ref RE: Regex = Regex::new("\\d+:[^\\s]+.*").unwrap();
+        static ref RE: Regex = Regex::new("^\\d+:[^\\s]+").unwrap();
     }
     RE.is_match(text)
 }
 
 use backtrace_on_stack_overflow;
-use std::io::{prelude::*, BufReader};
 
 
 fn main() -> std::io::Result<()> {
@@ -64,41 +292,47 @@ fn main() -> std::io::Result<()> {
 
     match cmd.as_str() {
         "add" => {
-            let new_command = env::args().nth(2).unwrap_or("".to_string());
+            let args: Vec<String> = env::args().skip(2).collect();
+            let (options, positional_args) = args.split_at(leading_option_count(&args));
+            let new_command = positional_args.first().cloned().unwrap_or("".to_string());
 
             if indexable_command(new_command.as_str()) {
-                let parts: Vec<&str> = new_command.trim().split(":").collect();
-                let exit_code = parts.get(0).unwrap().parse::<u64>().unwrap();
-                let command_input = parts.get(1).unwrap();
-                let index_path = build_index_path();
+                // Only split on the first `:`, the command itself can contain
+                // any number of them. Zsh passes the command with a trailing
+                // newline which isn't part of it.
+                let (exit_code, command_input) = new_command.split_once(':').unwrap();
+                let exit_code = exit_code.parse::<u64>().unwrap();
+                let command_input = command_input.trim_end_matches('\n');
 
                 let schema = build_schema();
+                let index = open_index(&schema);
 
-                if !index_path.exists() {
-                    fs::create_dir_all(&index_path).unwrap();
-                }
-
-                let directory: Box<dyn Directory> = Box::new(MmapDirectory::open(index_path).unwrap());
-
-                let settings = IndexSettings {
-                    sort_by_field: Some(IndexSortByField {
-                        field: "timestamp".to_string(),
-                        order: Order::Desc,
-                    }),
-                    ..Default::default()
-                };
-                let mut index_builder = Index::builder().schema(schema.clone());
-                index_builder = index_builder.settings(settings);
-                let index = index_builder.open_or_create(directory).unwrap();
-
-                // let index = Index::open_or_create(directory, schema.clone()).unwrap();
-
-                let mut index_writer = index.writer(30_000_000).unwrap();
+                let mut index_writer = open_index_writer(&index);
+                let searcher = build_searcher(&index);
                 let current_dir = std::env::current_dir().unwrap().to_str().unwrap().to_string();
+                let git = git_context(Path::new(&current_dir)).unwrap_or_default();
+
+                let entry = HistoryEntry {
+                    directory: current_dir,
+                    command: command_input.to_string(),
+                    exit_code,
+                    timestamp: Ulid::new().timestamp_ms(),
+                    duration_ms: option_value(options, "duration")
+                        .and_then(|duration_ms| duration_ms.parse::<u64>().ok())
+                        .unwrap_or(UNKNOWN_DURATION),
+                    hostname: option_value(options, "hostname").unwrap_or_default(),
+                    username: option_value(options, "username").unwrap_or_default(),
+                    session: option_value(options, "session").unwrap_or_default(),
+                    shell: option_value(options, "shell").unwrap_or_default(),
+                    tty: option_value(options, "tty").unwrap_or_default(),
+                    pid: option_value(options, "pid")
+                        .and_then(|pid| pid.parse::<u64>().ok())
+                        .unwrap_or(0),
+                    git,
+                    paths: vec![],
+                };
+                index_command(entry, CommandUses::runs(1), &schema, &searcher, &index_writer);
 
-                let document = index_command(current_dir, command_input.to_string(), &schema, &index, exit_code);
-
-                index_writer.add_document(document);
                 index_writer.commit().unwrap();
             } else {
                 // println!("Indexing failed, the command does't match the pattern \"<exit code>:<command>\"");
@@ -107,35 +341,125 @@ fn main() -> std::io::Result<()> {
             }
         }
         "search" => {
-            let fd_path = env::args().nth(2).unwrap_or("".to_string());
-            let initial_input = env::args().nth(3).unwrap_or("".to_string());
+            let args: Vec<String> = env::args().skip(2).collect();
+            let (options, positional_args) = args.split_at(leading_option_count(&args));
+
+            let fd_path = positional_args.first().cloned().unwrap_or("".to_string());
+            let initial_input = positional_args.get(1).cloned().unwrap_or("".to_string());
+
+            let mode_name = option_value(options, "mode").unwrap_or("fuzzy".to_string());
+            let mode = match SearchMode::from_name(&mode_name) {
+                Some(mode) => mode,
+                None => {
+                    eprintln!("A valid search mode is required. Supported: \"fuzzy\", \"prefix\", \"substring\", \"word\", \"regex\".");
+                    std::process::exit(1);
+                }
+            };
+
+            let scope_name = option_value(options, "scope").unwrap_or("global".to_string());
+            let scope = match SearchScope::from_name(&scope_name) {
+                Some(scope) => scope,
+                None => {
+                    eprintln!("A valid search scope is required. Supported: \"global\", \"directory\", \"subtree\", \"repo\", \"session\".");
+                    std::process::exit(1);
+                }
+            };
+            let session = option_value(options, "session").unwrap_or_default();
+
+            // 0, or no limit at all, loads every result a page at a time
+            let limit = match option_value(options, "limit").map(|limit| limit.parse::<usize>()) {
+                Some(Ok(0)) | None => None,
+                Some(Ok(limit)) => Some(limit),
+                Some(Err(_)) => {
+                    eprintln!("--limit must be a number of results, or 0 for no limit.");
+                    std::process::exit(1);
+                }
+            };
 
-            if let Ok(selection) = interactive_search_command(fd_path, initial_input) {
+            if let Ok(selection) = interactive_search_command(fd_path, initial_input, mode, scope, session, limit) {
                 // This is captured by `fzh-widget` in fzh.zsh then executed as a
                 // shell command.
                 println!("{}", selection);
+
+                // The shell waits for this process to exit before it runs the
+                // command, so the pick is written by another one. It doesn't
+                // inherit stdout, which the shell is reading until it closes.
+                std::process::Command::new(env::current_exe().unwrap())
+                    .arg("selected")
+                    .arg(&selection)
+                    .stdin(std::process::Stdio::null())
+                    .stdout(std::process::Stdio::null())
+                    .stderr(std::process::Stdio::null())
+                    .spawn()
+                    .ok();
             }
         }
+        "selected" => {
+            let command = env::args().nth(2).unwrap_or("".to_string());
+
+            let schema = build_schema();
+            let index = open_index(&schema);
+            let current_dir = std::env::current_dir().unwrap().to_str().unwrap().to_string();
+
+            record_selection(&index, &command, &current_dir);
+        }
         "import" => {
             let shell_type = env::args().nth(2).unwrap_or("".to_string());
 
-            if shell_type != "zsh" {
-                println!("A valid shell type is required. Only \"zsh\" is currently supported.");
+            let default_history_path = match shell_type.as_str() {
+                "zsh" => Path::new(&home_dir().unwrap()).join(".zsh_history"),
+                "bash" => Path::new(&home_dir().unwrap()).join(".bash_history"),
+                "fish" => default_fish_history_path(),
+                "atuin" => data_home_path().join("atuin").join("history.db"),
+                "histdb" => Path::new(&home_dir().unwrap()).join(".histdb").join("zsh-history.db"),
+                "mcfly" => default_mcfly_history_path(),
+                _ => {
+                    println!("A valid history source is required. Supported: \"zsh\", \"bash\", \"fish\", \"atuin\", \"histdb\", \"mcfly\".");
+                    std::process::exit(1);
+                }
+            };
+            let default_history_string = default_history_path.to_str().unwrap().to_string();
+            let history_string = env::args().nth(3).unwrap_or(default_history_string);
+            let history_path = Path::new(&history_string);
+
+            if !history_path.is_file() {
+                println!("Import failed, unable to find shell history path: {:#?}", history_path);
+                println!("Import usage: fzh import <shell> <shell history path>");
                 std::process::exit(1);
             }
 
-            let default_zsh_history_path = Path::new(&home_dir().unwrap()).join(".zsh_history").to_str().unwrap().to_string();
-            let zsh_history_string = env::args().nth(3).unwrap_or(default_zsh_history_path);
-            let zsh_history_path = Path::new(&zsh_history_string);
+            match shell_type.as_str() {
+                "zsh" => import_zsh_history(history_path),
+                "bash" => import_bash_history(history_path),
+                "fish" => import_fish_history(history_path),
+                "atuin" => import_atuin_history(history_path),
+                "histdb" => import_histdb_history(history_path),
+                "mcfly" => import_mcfly_history(history_path),
+                _ => unreachable!(),
+            }
+
+            println!("Import finished. Thanks for using Fzh, you're awesome! (ﾉ^_^)ﾉ❤️");
+        }
+        "export" => {
+            let args: Vec<String> = env::args().skip(2).collect();
+            let format = option_value(&args, "format").unwrap_or("jsonl".to_string());
 
-            if !zsh_history_path.exists() && zsh_history_path.is_file() {
-                println!("Import failed, unable to find shell history path: {:#?}", zsh_history_path);
-                println!("Import usage: fzh import <shell> <shell history path>");
+            if !["jsonl", "csv", "zsh", "bash", "fish"].contains(&format.as_str()) {
+                println!("A valid export format is required. Supported: \"jsonl\", \"csv\", \"zsh\", \"bash\", \"fish\".");
+                println!("Export usage: fzh export --format <format> [--output <path>]");
                 std::process::exit(1);
             }
 
-            import_zsh_history(zsh_history_path);
-            println!("Import finished. Thanks for using Fzh, you're awesome! (ﾉ^_^)ﾉ❤️");
+            let mut output: Box<dyn io::Write> = match option_value(&args, "output") {
+                Some(output_path) => Box::new(io::BufWriter::new(fs::File::create(output_path)?)),
+                None => Box::new(io::BufWriter::new(io::stdout())),
+            };
+
+            export_history(format.as_str(), &mut output)?;
+            output.flush()?;
+        }
+        "session_id" => {
+            println!("{}", Ulid::new());
         }
         "delete_index" => {
             let index_path = build_index_path();
@@ -145,12 +469,16 @@ fn main() -> std::io::Result<()> {
         "init" => {
             let shell_type = env::args().nth(2).unwrap_or("".to_string());
 
-            if shell_type != "zsh" {
-                println!("A valid shell type is required. Only \"zsh\" is currently supported.");
-                std::process::exit(1);
-            }
+            let script = match shell_type.as_str() {
+                "zsh" => include_str!("../fzh.zsh"),
+                "bash" => include_str!("../fzh.bash"),
+                "fish" => include_str!("../fzh.fish"),
+                _ => {
+                    println!("A valid shell type is required. Supported shells: \"zsh\", \"bash\", \"fish\".");
+                    std::process::exit(1);
+                }
+            };
 
-            let script = include_str!("../fzh.zsh");
             print!("{}", script);
         }
         _ => {
@@ -162,252 +490,1909 @@ fn main() -> std::io::Result<()> {
 }
 
 fn import_zsh_history(zsh_history_path: &Path) {
-    // Open the file
-    let file = fs::File::open(zsh_history_path).unwrap();
-
-    // Create a BufReader to read the file line by line
-    let reader = BufReader::new(file);
-
-    let index_path = build_index_path();
-    let schema = build_schema();
-
-    if !index_path.exists() {
-        fs::create_dir_all(&index_path).unwrap();
-    }
-
-    let directory: Box<dyn Directory> = Box::new(MmapDirectory::open(index_path).unwrap());
-    let settings = IndexSettings {
-        sort_by_field: Some(IndexSortByField {
-            field: "timestamp".to_string(),
-            order: Order::Desc,
-        }),
-        ..Default::default()
-    };
-    let mut index_builder = Index::builder().schema(schema.clone());
-    index_builder = index_builder.settings(settings);
-    let index = index_builder.open_or_create(directory).unwrap();
+    // Read raw bytes, the file isn't valid UTF-8 until it's unmetafied
+    let file_contents = fs::read(zsh_history_path).unwrap();
 
+    let mut entries = vec![];
 
-    // let index = Index::open_or_create(directory, schema.clone()).unwrap();
+    // Extended history format, `: <start time>:<elapsed seconds>;<command>`
+    let re = Regex::new(r"(?s)^: (?P<timestamp>\d+):(?P<duration>\d+);(?P<new_command>.*)$").unwrap();
 
-    let mut index_writer = index.writer(30_000_000).unwrap();
+    // Zsh writes each newline inside a command (heredocs, loops, `\`
+    // continuations) as a backslash at the end of the line, so lines are joined
+    // until one doesn't end in a lone backslash.
+    let mut history_line = String::new();
+    let mut unreadable_lines = 0;
 
     // Iterate over the lines in the file
-    for line in reader.lines() {
+    for raw_line in file_contents.split(|byte| *byte == b'\n') {
         // Process each line
-        match line {
+        match String::from_utf8(unmetafy(raw_line)) {
             Ok(line) => {
-                let re = Regex::new(r": (?P<timestamp>\d+):\d+;(?P<new_command>[^\\s]+.*)").unwrap();
-                if let Some(captures) = re.captures(line.as_str()) {
-                    let timestamp = captures.name("timestamp").unwrap().as_str();
+                if line.ends_with('\\') && !line.ends_with("\\\\") {
+                    history_line.push_str(&line[..line.len() - 1]);
+                    history_line.push('\n');
+                    continue;
+                }
+
+                history_line.push_str(&line);
+                let full_line = std::mem::take(&mut history_line);
+
+                if let Some(captures) = re.captures(full_line.as_str()) {
+                    let timestamp = captures.name("timestamp").unwrap().as_str().parse::<u64>().unwrap();
+                    let duration = captures.name("duration").unwrap().as_str().parse::<u64>().unwrap();
                     let new_command = captures.name("new_command").unwrap().as_str();
-                    let new_command = format!("{}:{}", timestamp, new_command);
 
                     if new_command.trim().is_empty() {
                         continue;
                     }
 
-                    let parts: Vec<&str> = new_command.trim().split(":").collect();
-                    let exit_code = parts.get(0).unwrap().parse::<u64>().unwrap();
-                    let command_input = parts.get(1).unwrap();
-
-                    let document = index_command("".to_string(), command_input.to_string(), &schema, &index, exit_code);
+                    let entry = HistoryEntry {
+                        command: new_command.to_string(),
+                        timestamp: timestamp * 1000,
+                        duration_ms: duration * 1000,
+                        ..Default::default()
+                    };
 
-                    index_writer.add_document(document);
+                    entries.push(entry);
                 }
             },
-            Err(e) => eprintln!("Error reading line: {}", e),
+            Err(_) => unreadable_lines += 1,
         }
     }
 
-    index_writer.commit().unwrap();
+    index_entries(zsh_history_path, entries);
+
+    if unreadable_lines > 0 {
+        println!("Skipped {} history lines that could not be decoded as UTF-8.", unreadable_lines);
+    }
 }
 
-fn build_schema() -> Schema {
-    let mut schema_builder = Schema::builder();
+// Zsh "metafies" the history file: any byte that's special to the shell is
+// written as the Meta byte (0x83) followed by the original byte XOR 0x20. Those
+// bytes are the high half of most multi-byte UTF-8 characters, so accents, CJK
+// and emoji are all invalid UTF-8 until this is reversed.
+fn unmetafy(bytes: &[u8]) -> Vec<u8> {
+    const META: u8 = 0x83;
 
-    schema_builder.add_u64_field("id", FAST | INDEXED | STORED);
-    schema_builder.add_u64_field("timestamp", FAST | INDEXED | STORED);
-    schema_builder.add_u64_field("times_selected", FAST | INDEXED | STORED);
-    schema_builder.add_u64_field("exit_code", FAST | INDEXED | STORED);
-    schema_builder.add_text_field(
-        "directory",
-        TextOptions::default()
-            .set_indexing_options(
-                TextFieldIndexing::default()
-                    .set_tokenizer("raw")
-                    .set_index_option(IndexRecordOption::Basic),
-            )
-            .set_stored(),
-    );
-    schema_builder.add_text_field(
-        "command",
-        TextOptions::default()
-            .set_indexing_options(
-                TextFieldIndexing::default()
-                    .set_tokenizer("raw")
-                    .set_index_option(IndexRecordOption::Basic),
-            )
-            .set_stored(),
-    );
+    let mut unmetafied = Vec::with_capacity(bytes.len());
+    let mut bytes = bytes.iter();
 
-    schema_builder.build()
+    while let Some(&byte) = bytes.next() {
+        if byte == META {
+            if let Some(&escaped) = bytes.next() {
+                unmetafied.push(escaped ^ 0x20);
+            }
+        } else {
+            unmetafied.push(byte);
+        }
+    }
+
+    unmetafied
 }
 
-fn build_index_path() -> std::path::PathBuf {
-    Path::new(&home_dir().unwrap()).join(".fzh")
+// With `HISTTIMEFORMAT` set, bash writes a `#<unix seconds>` comment before each
+// command and every line up to the next comment belongs to the same command.
+// Without it there's one command per line and no times at all.
+fn import_bash_history(bash_history_path: &Path) {
+    let file_contents = fs::read(bash_history_path).unwrap();
+    let file_contents = String::from_utf8_lossy(&file_contents);
+
+    lazy_static! {
+        static ref TIMESTAMP_RE: Regex = Regex::new(r"^#(?P<timestamp>\d{9,})$").unwrap();
+    }
+
+    let mut entries: Vec<(Option<u64>, String)> = vec![];
+
+    for line in file_contents.lines() {
+        if let Some(captures) = TIMESTAMP_RE.captures(line) {
+            let timestamp = captures.name("timestamp").unwrap().as_str().parse::<u64>().ok();
+            entries.push((timestamp, "".to_string()));
+            continue;
+        }
+
+        match entries.last_mut() {
+            // Multi-line commands are only recoverable when timestamps separate them
+            Some((Some(_), command)) => {
+                if !command.is_empty() {
+                    command.push('\n');
+                }
+                command.push_str(line);
+            }
+            _ => entries.push((None, line.to_string())),
+        }
+    }
+
+    // Entries without a time get one second before the entry that follows them,
+    // counting back from when the file was last written. This keeps the file
+    // order so recency still ranks the end of the history first.
+    let modified_ms = fs::metadata(bash_history_path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|modified| modified.duration_since(std::time::UNIX_EPOCH).ok())
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or_else(|| Ulid::new().timestamp_ms());
+
+    let mut next_timestamp = modified_ms;
+    let mut timestamps = vec![0; entries.len()];
+
+    for (i, (timestamp, _)) in entries.iter().enumerate().rev() {
+        next_timestamp = match timestamp {
+            Some(seconds) => seconds * 1000,
+            None => next_timestamp.saturating_sub(1000),
+        };
+        timestamps[i] = next_timestamp;
+    }
+
+    let entries = entries
+        .into_iter()
+        .zip(timestamps)
+        .map(|((_, command), timestamp)| HistoryEntry {
+            command,
+            timestamp,
+            ..Default::default()
+        })
+        .collect();
+
+    index_entries(bash_history_path, entries);
 }
 
-fn index_command(command_directory: String, new_command: String, schema: &Schema, index: &Index, exit_code: u64) -> Document {
-    let id_field = schema.get_field("id").unwrap();
-    let timestamp_field = schema.get_field("timestamp").unwrap();
-    let times_selected_field = schema.get_field("times_selected").unwrap();
-    let exit_code_field = schema.get_field("exit_code").unwrap();
-    let command_field = schema.get_field("command").unwrap();
-    let directory_field = schema.get_field("directory").unwrap();
+fn data_home_path() -> std::path::PathBuf {
+    match env::var("XDG_DATA_HOME") {
+        Ok(path) if !path.is_empty() => Path::new(&path).to_path_buf(),
+        _ => Path::new(&home_dir().unwrap()).join(".local").join("share"),
+    }
+}
 
-    let mut command_doc = Document::default();
+fn default_fish_history_path() -> std::path::PathBuf {
+    data_home_path().join("fish").join("fish_history")
+}
 
-    let combined_string = format!("{} {}", command_directory, new_command);
-    let id_string = combined_string.as_str();
-    let assigned_id = city::hash64(id_string);
+struct FishHistoryEntry {
+    command: String,
+    timestamp: u64,
+    paths: Vec<String>,
+}
 
+// Fish writes its history as a YAML-like list:
+//
+//   - cmd: cargo test
+//     when: 1681234567
+//     paths:
+//       - src/main.rs
+//
+// Newlines and backslashes in commands and paths are escaped as `\n` and `\\`.
+fn import_fish_history(fish_history_path: &Path) {
+    let file_contents = fs::read(fish_history_path).unwrap();
+
+    index_entries(fish_history_path, fish_history_entries(&String::from_utf8_lossy(&file_contents)));
+}
 
-    // start
-    let reader = index
-        .reader_builder()
-        .reload_policy(ReloadPolicy::OnCommit)
-        .try_into().unwrap();
+fn fish_history_entries(file_contents: &str) -> Vec<HistoryEntry> {
+    let mut entries: Vec<FishHistoryEntry> = vec![];
+    let mut in_paths = false;
+
+    for line in file_contents.lines() {
+        if let Some(command) = line.strip_prefix("- cmd: ") {
+            entries.push(FishHistoryEntry {
+                command: unescape_fish_history(command),
+                timestamp: 0,
+                paths: vec![],
+            });
+            in_paths = false;
+            continue;
+        }
 
-    let searcher = reader.searcher();
+        let entry = match entries.last_mut() {
+            Some(entry) => entry,
+            None => continue,
+        };
+
+        if let Some(when) = line.strip_prefix("  when: ") {
+            entry.timestamp = when.trim().parse::<u64>().unwrap_or(0);
+            in_paths = false;
+        } else if line == "  paths:" {
+            in_paths = true;
+        } else if in_paths {
+            if let Some(path) = line.strip_prefix("    - ") {
+                entry.paths.push(unescape_fish_history(path));
+            }
+        }
+    }
+
+    entries
+        .into_iter()
+        .map(|entry| {
+            let timestamp = if entry.timestamp > 0 {
+                entry.timestamp * 1000
+            } else {
+                Ulid::new().timestamp_ms()
+            };
+
+            // Fish doesn't record the directory a command ran in
+            HistoryEntry {
+                command: entry.command,
+                timestamp,
+                paths: entry.paths,
+                ..Default::default()
+            }
+        })
+        .collect()
+}
+
+fn unescape_fish_history(text: &str) -> String {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut chars = text.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some('n') => unescaped.push('\n'),
+            Some('\\') => unescaped.push('\\'),
+            Some(other) => {
+                unescaped.push('\\');
+                unescaped.push(other);
+            }
+            None => unescaped.push('\\'),
+        }
+    }
+
+    unescaped
+}
+
+// Atuin keeps its history in SQLite with nanosecond times and durations, -1
+// for anything it didn't capture, and `hostname` stored as "<host>:<user>".
+fn import_atuin_history(atuin_db_path: &Path) {
+    let connection = rusqlite::Connection::open_with_flags(
+        atuin_db_path,
+        rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY,
+    )
+    .unwrap();
+
+    // `deleted_at` was added in later versions of atuin
+    let has_deleted_at = connection.prepare("SELECT deleted_at FROM history LIMIT 1").is_ok();
+    let query = if has_deleted_at {
+        "SELECT timestamp, duration, exit, command, cwd, session, hostname FROM history WHERE deleted_at IS NULL"
+    } else {
+        "SELECT timestamp, duration, exit, command, cwd, session, hostname FROM history"
+    };
+
+    let mut statement = connection.prepare(query).unwrap();
+    let rows = statement
+        .query_map([], |row| {
+            let timestamp: i64 = row.get(0)?;
+            let duration: i64 = row.get(1)?;
+            let exit: i64 = row.get(2)?;
+            let host_and_user: String = row.get(6)?;
+            let (hostname, username) = host_and_user
+                .split_once(':')
+                .unwrap_or((host_and_user.as_str(), ""));
+
+            Ok(HistoryEntry {
+                directory: row.get(4)?,
+                command: row.get(3)?,
+                exit_code: if exit < 0 { UNKNOWN_EXIT_CODE } else { exit as u64 },
+                timestamp: (timestamp.max(0) / 1_000_000) as u64,
+                duration_ms: if duration < 0 { UNKNOWN_DURATION } else { (duration / 1_000_000) as u64 },
+                hostname: hostname.to_string(),
+                username: username.to_string(),
+                session: row.get(5)?,
+                ..Default::default()
+            })
+        })
+        .unwrap();
+
+    index_sqlite_rows(rows, "atuin", atuin_db_path);
+}
+
+// zsh-histdb normalises commands and places (host + directory) into their own
+// tables. Times and durations are in seconds, `exit_status` is NULL for
+// commands that never finished.
+fn import_histdb_history(histdb_path: &Path) {
+    let connection = rusqlite::Connection::open_with_flags(
+        histdb_path,
+        rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY,
+    )
+    .unwrap();
+
+    let mut statement = connection
+        .prepare(
+            "SELECT commands.argv, places.dir, places.host, history.session, history.exit_status, history.start_time, history.duration
+            FROM history
+            JOIN commands ON history.command_id = commands.id
+            JOIN places ON history.place_id = places.id",
+        )
+        .unwrap();
+    let rows = statement
+        .query_map([], |row| {
+            let session: Option<i64> = row.get(3)?;
+            let exit_status: Option<i64> = row.get(4)?;
+            let start_time: Option<i64> = row.get(5)?;
+            let duration: Option<i64> = row.get(6)?;
+
+            Ok(HistoryEntry {
+                command: row.get::<_, Option<String>>(0)?.unwrap_or_default(),
+                directory: row.get::<_, Option<String>>(1)?.unwrap_or_default(),
+                hostname: row.get::<_, Option<String>>(2)?.unwrap_or_default(),
+                session: session.map(|session| session.to_string()).unwrap_or_default(),
+                exit_code: match exit_status {
+                    Some(exit_status) if exit_status >= 0 => exit_status as u64,
+                    _ => UNKNOWN_EXIT_CODE,
+                },
+                timestamp: start_time.unwrap_or(0).max(0) as u64 * 1000,
+                duration_ms: match duration {
+                    Some(duration) if duration >= 0 => duration as u64 * 1000,
+                    _ => UNKNOWN_DURATION,
+                },
+                ..Default::default()
+            })
+        })
+        .unwrap();
+
+    index_sqlite_rows(rows, "histdb", histdb_path);
+}
+
+fn default_mcfly_history_path() -> std::path::PathBuf {
+    // Older versions of McFly kept everything in ~/.mcfly
+    let legacy_path = Path::new(&home_dir().unwrap()).join(".mcfly").join("history.db");
+
+    if legacy_path.exists() {
+        legacy_path
+    } else {
+        data_home_path().join("mcfly").join("history.db")
+    }
+}
+
+// McFly stores one row per run with times in seconds and no duration.
+fn import_mcfly_history(mcfly_db_path: &Path) {
+    let connection = rusqlite::Connection::open_with_flags(
+        mcfly_db_path,
+        rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY,
+    )
+    .unwrap();
+
+    let mut statement = connection
+        .prepare("SELECT cmd, dir, session_id, exit_code, when_run FROM commands")
+        .unwrap();
+    let rows = statement
+        .query_map([], |row| {
+            let exit_code: Option<i64> = row.get(3)?;
+            let when_run: Option<i64> = row.get(4)?;
+
+            Ok(HistoryEntry {
+                command: row.get(0)?,
+                directory: row.get::<_, Option<String>>(1)?.unwrap_or_default(),
+                session: row.get::<_, Option<String>>(2)?.unwrap_or_default(),
+                exit_code: match exit_code {
+                    Some(exit_code) if exit_code >= 0 => exit_code as u64,
+                    _ => UNKNOWN_EXIT_CODE,
+                },
+                timestamp: when_run.unwrap_or(0).max(0) as u64 * 1000,
+                ..Default::default()
+            })
+        })
+        .unwrap();
+
+    index_sqlite_rows(rows, "mcfly", mcfly_db_path);
+}
+
+fn index_sqlite_rows(rows: impl Iterator<Item = rusqlite::Result<HistoryEntry>>, source: &str, path: &Path) {
+    let mut entries = vec![];
+
+    for entry in rows {
+        match entry {
+            Ok(entry) => entries.push(entry),
+            Err(e) => eprintln!("Error reading {} history row: {}", source, e),
+        }
+    }
+
+    index_entries(path, entries);
+}
+
+/// Indexes the entries imported from a history file, skipping the ones an
+/// earlier import of the same file already counted.
+fn index_entries(history_path: &Path, entries: Vec<HistoryEntry>) {
+    let schema = build_schema();
+    let index = open_index(&schema);
+
+    let source = fs::canonicalize(history_path)
+        .unwrap_or_else(|_| history_path.to_path_buf())
+        .to_string_lossy()
+        .to_string();
+    let mut import_marks = read_import_marks();
+    let mut import_mark = import_marks.get(&source).copied();
+    let entries = skip_imported_entries(entries, &mut import_mark);
+
+    import_entries(&index, entries);
+
+    if let Some(import_mark) = import_mark {
+        import_marks.insert(source, import_mark);
+        write_import_marks(&import_marks);
+    }
+}
+
+/// Where each history file was imported up to, in milliseconds, kept next to
+/// the index so deleting it starts over.
+fn import_marks_path(index_path: &Path) -> std::path::PathBuf {
+    index_path.join("imports.json")
+}
+
+fn read_import_marks() -> HashMap<String, u64> {
+    fs::read_to_string(import_marks_path(&build_index_path()))
+        .ok()
+        .and_then(|contents| serde_json::from_str(&contents).ok())
+        .unwrap_or_default()
+}
+
+fn write_import_marks(import_marks: &HashMap<String, u64>) {
+    fs::write(import_marks_path(&build_index_path()), serde_json::to_string(import_marks).unwrap()).unwrap();
+}
+
+/// Drops the entries at or before `import_mark`, and moves it up to the latest
+/// entry that's left.
+fn skip_imported_entries(entries: Vec<HistoryEntry>, import_mark: &mut Option<u64>) -> Vec<HistoryEntry> {
+    let entries: Vec<HistoryEntry> = entries
+        .into_iter()
+        .filter(|entry| import_mark.map_or(true, |import_mark| entry.timestamp > import_mark))
+        .collect();
+
+    *import_mark = entries.iter().map(|entry| entry.timestamp).max().or(*import_mark);
+
+    entries
+}
+
+/// Indexes a batch of imported entries in a single commit. Repeats of the same
+/// command in the same directory are folded into their latest run first, the
+/// searcher that carries counters forward can't see uncommitted documents.
+fn import_entries(index: &Index, entries: Vec<HistoryEntry>) {
+    let schema = index.schema();
+    let mut index_writer = open_index_writer(index);
+    let searcher = build_searcher(index);
+
+    let mut latest_entries: HashMap<u64, (HistoryEntry, CommandUses)> = HashMap::new();
+    let half_life_ms = frecency_half_life_ms();
+
+    for mut entry in entries {
+        if entry.command.trim().is_empty() {
+            continue;
+        }
+
+        // Databases with a nullable directory column
+        if entry.directory.is_empty() {
+            entry.directory = UNKNOWN_DIRECTORY.to_string();
+        }
+
+        let id = command_id(&entry.directory, &entry.command);
+
+        match latest_entries.get_mut(&id) {
+            Some(latest_entry) => merge_entry(latest_entry, entry, CommandUses::runs(1), half_life_ms),
+            None => {
+                latest_entries.insert(id, (entry, CommandUses::runs(1)));
+            }
+        }
+    }
 
-    let query_parser = QueryParser::for_index(&index, vec![id_field]);
+    let latest_entries = fold_unknown_directories(latest_entries.into_values().collect(), half_life_ms);
 
-    // oof, whatever moving on.
-    let query = query_parser.parse_query(assigned_id.to_string().as_str()).unwrap();
-    let top_docs = searcher.search(&query, &TopDocs::with_limit(1)).unwrap();
+    for (entry, uses) in latest_entries {
+        index_command(entry, uses, &schema, &searcher, &index_writer);
+    }
+
+    index_writer.commit().unwrap();
+}
+
+/// Adds an entry's uses to another entry of the same command, keeping the
+/// details of whichever ran last and the frecency as of that run.
+fn merge_entry(latest: &mut (HistoryEntry, CommandUses), entry: HistoryEntry, uses: CommandUses, half_life_ms: u64) {
+    let (latest_entry, latest_uses) = latest;
+    latest_uses.runs += uses.runs;
+    latest_uses.selections += uses.selections;
+
+    if entry.timestamp >= latest_entry.timestamp {
+        latest_uses.frecency =
+            decay_frecency(latest_uses.frecency, latest_entry.timestamp, entry.timestamp, half_life_ms) + uses.frecency;
+        *latest_entry = entry;
+    } else {
+        latest_uses.frecency += decay_frecency(uses.frecency, entry.timestamp, latest_entry.timestamp, half_life_ms);
+    }
+}
+
+/// Folds entries without a directory into the latest entry of the same command
+/// that has one, like `index_command` does with indexed copies. A batch is
+/// written in a single commit, so `index_command` can't see the rest of it.
+fn fold_unknown_directories(
+    entries: Vec<(HistoryEntry, CommandUses)>,
+    half_life_ms: u64,
+) -> Vec<(HistoryEntry, CommandUses)> {
+    let (unknown_entries, mut entries): (Vec<_>, Vec<_>) =
+        entries.into_iter().partition(|(entry, _)| entry.directory == UNKNOWN_DIRECTORY);
+
+    let mut latest_by_command: HashMap<String, usize> = HashMap::new();
+
+    for (i, (entry, _)) in entries.iter().enumerate() {
+        let latest = latest_by_command.entry(entry.command.clone()).or_insert(i);
+
+        if entries[*latest].0.timestamp < entry.timestamp {
+            *latest = i;
+        }
+    }
+
+    for (mut entry, uses) in unknown_entries {
+        match latest_by_command.get(&entry.command) {
+            Some(&i) => {
+                entry.directory = entries[i].0.directory.clone();
+                merge_entry(&mut entries[i], entry, uses, half_life_ms);
+            }
+            None => entries.push((entry, uses)),
+        }
+    }
+
+    entries
+}
+
+fn export_history(format: &str, output: &mut dyn io::Write) -> io::Result<()> {
+    let schema = build_schema();
+    let index = open_index(&schema);
+    let reader = index.reader().unwrap();
+    let searcher = reader.searcher();
+
+    let timestamp_field = schema.get_field("timestamp").unwrap();
+    let duration_ms_field = schema.get_field("duration_ms").unwrap();
+    let command_field = schema.get_field("command").unwrap();
+
+    // Fields that are only indexed, like `directory_ancestors`, have nothing
+    // to export
+    let stored_fields: Vec<(Field, &str)> = schema
+        .fields()
+        .filter(|(_, field_entry)| field_entry.is_stored())
+        .map(|(field, field_entry)| (field, field_entry.name()))
+        .collect();
+
+    let doc_addresses = searcher.search(&AllQuery, &DocSetCollector).unwrap();
+    let mut documents: Vec<Document> = doc_addresses
+        .into_iter()
+        .map(|doc_address| searcher.doc(doc_address).unwrap())
+        .collect();
+
+    // Oldest first, the same order a shell appends to its history file
+    documents.sort_by_key(|doc| stored_u64(doc, timestamp_field));
+
+    if format == "csv" {
+        let header: Vec<&str> = stored_fields.iter().map(|(_, name)| *name).collect();
+        writeln!(output, "{}", header.join(","))?;
+    }
+
+    for doc in documents {
+        let command = doc
+            .get_first(command_field)
+            .and_then(|value| value.as_text())
+            .unwrap_or("");
+        let timestamp_seconds = stored_u64(&doc, timestamp_field) / 1000;
+
+        match format {
+            "jsonl" => {
+                let mut object = serde_json::Map::new();
+
+                for (field, name) in &stored_fields {
+                    object.insert(name.to_string(), export_value(&doc, *field, name));
+                }
+
+                writeln!(output, "{}", serde_json::Value::Object(object))?;
+            }
+            "csv" => {
+                let row: Vec<String> = stored_fields
+                    .iter()
+                    .map(|(field, name)| match export_value(&doc, *field, name) {
+                        serde_json::Value::Null => "".to_string(),
+                        serde_json::Value::String(text) => csv_field(&text),
+                        value => csv_field(&value.to_string()),
+                    })
+                    .collect();
+
+                writeln!(output, "{}", row.join(","))?;
+            }
+            "zsh" => {
+                // The extended format always has a duration, unknown ones are
+                // written as 0 like commands that finished within a second
+                let duration_ms = stored_u64(&doc, duration_ms_field);
+                let duration_seconds = if duration_ms == UNKNOWN_DURATION { 0 } else { duration_ms / 1000 };
+                let line = format!(": {}:{};{}\n", timestamp_seconds, duration_seconds, command.replace('\n', "\\\n"));
+
+                output.write_all(&metafy(line.as_bytes()))?;
+            }
+            "bash" => {
+                writeln!(output, "#{}\n{}", timestamp_seconds, command)?;
+            }
+            "fish" => {
+                writeln!(output, "- cmd: {}\n  when: {}", escape_fish_history(command), timestamp_seconds)?;
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    Ok(())
+}
+
+fn stored_u64(doc: &Document, field: Field) -> u64 {
+    doc.get_first(field).and_then(|value| value.as_u64()).unwrap_or(0)
+}
+
+fn stored_f64(doc: &Document, field: Field) -> f64 {
+    doc.get_first(field).and_then(|value| value.as_f64()).unwrap_or(0.0)
+}
+
+fn stored_string(doc: &Document, field: Field) -> String {
+    match doc.get_first(field) {
+        Some(Value::Str(text)) => text.to_string(),
+        Some(Value::U64(number)) => number.to_string(),
+        Some(Value::I64(number)) => number.to_string(),
+        Some(Value::F64(number)) => number.to_string(),
+        _ => "".to_string(),
+    }
+}
+
+fn stored_json(doc: &Document, field: Field) -> serde_json::Value {
+    match doc.get_first(field) {
+        Some(Value::Str(text)) => serde_json::Value::from(text.as_str()),
+        Some(Value::U64(number)) => serde_json::Value::from(*number),
+        Some(Value::I64(number)) => serde_json::Value::from(*number),
+        Some(Value::F64(number)) => serde_json::Value::from(*number),
+        _ => serde_json::Value::Null,
+    }
+}
+
+/// A stored value for the JSON Lines and CSV exports, `null` for values the
+/// history never recorded rather than their placeholders. `paths` is a list.
+fn export_value(doc: &Document, field: Field, name: &str) -> serde_json::Value {
+    if name == "paths" {
+        return doc.get_all(field).filter_map(|value| value.as_text()).collect();
+    }
+
+    let unknown = match (name, doc.get_first(field)) {
+        ("exit_code", Some(Value::U64(exit_code))) => *exit_code == UNKNOWN_EXIT_CODE,
+        ("duration_ms", Some(Value::U64(duration_ms))) => *duration_ms == UNKNOWN_DURATION,
+        ("directory", Some(Value::Str(directory))) => directory == UNKNOWN_DIRECTORY,
+        _ => false,
+    };
+
+    if unknown {
+        serde_json::Value::Null
+    } else {
+        stored_json(doc, field)
+    }
+}
+
+fn csv_field(text: &str) -> String {
+    if text.contains(|c| matches!(c, ',' | '"' | '\n' | '\r')) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_string()
+    }
+}
+
+// The reverse of `unmetafy`, zsh expects these bytes escaped when it reads the
+// history file back.
+fn metafy(bytes: &[u8]) -> Vec<u8> {
+    const META: u8 = 0x83;
+
+    let mut metafied = Vec::with_capacity(bytes.len());
+
+    for &byte in bytes {
+        if byte == 0 || (META..=0xA2).contains(&byte) {
+            metafied.push(META);
+            metafied.push(byte ^ 0x20);
+        } else {
+            metafied.push(byte);
+        }
+    }
+
+    metafied
+}
+
+fn escape_fish_history(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+fn build_schema() -> Schema {
+    let mut schema_builder = Schema::builder();
+
+    schema_builder.add_u64_field("id", FAST | INDEXED | STORED);
+    schema_builder.add_u64_field("timestamp", FAST | INDEXED | STORED);
+    // Executions, and picks from the search client
+    schema_builder.add_u64_field("run_count", FAST | INDEXED | STORED);
+    schema_builder.add_u64_field("selected_count", FAST | INDEXED | STORED);
+    // Runs that decay by half every half-life, as of `timestamp`
+    schema_builder.add_f64_field("frecency", FAST | STORED);
+    schema_builder.add_u64_field("exit_code", FAST | INDEXED | STORED);
+    schema_builder.add_u64_field("duration_ms", FAST | INDEXED | STORED);
+    schema_builder.add_u64_field("pid", INDEXED | STORED);
+    schema_builder.add_text_field(
+        "directory",
+        TextOptions::default()
+            .set_indexing_options(
+                TextFieldIndexing::default()
+                    .set_tokenizer("raw")
+                    .set_index_option(IndexRecordOption::Basic),
+            )
+            .set_stored(),
+    );
+    // The directory and every directory above it, so `dir:` filters and scopes
+    // can match a whole subtree with one term.
+    schema_builder.add_text_field(
+        "directory_ancestors",
+        TextOptions::default().set_indexing_options(
+            TextFieldIndexing::default()
+                .set_tokenizer("raw")
+                .set_index_option(IndexRecordOption::Basic),
+        ),
+    );
+    schema_builder.add_text_field(
+        "command",
+        TextOptions::default()
+            .set_indexing_options(
+                TextFieldIndexing::default()
+                    .set_tokenizer("raw")
+                    .set_index_option(IndexRecordOption::Basic),
+            )
+            .set_stored(),
+    );
+
+    for raw_text_field in [
+        "hostname",
+        "username",
+        "session",
+        "shell",
+        "tty",
+        "git_root",
+        "git_branch",
+        "git_path",
+        "git_repository",
+    ] {
+        schema_builder.add_text_field(
+            raw_text_field,
+            TextOptions::default()
+                .set_indexing_options(
+                    TextFieldIndexing::default()
+                        .set_tokenizer("raw")
+                        .set_index_option(IndexRecordOption::Basic),
+                )
+                .set_stored(),
+        );
+    }
+
+    // Arguments fish recorded as paths, e.g. `/etc/hosts` for `vim /etc/hosts`.
+    // They're rarely the directory the command ran in, so they're kept apart.
+    schema_builder.add_text_field("paths", STORED);
+
+    schema_builder.build()
+}
+
+fn build_index_path() -> std::path::PathBuf {
+    Path::new(&home_dir().unwrap()).join(".fzh")
+}
+
+fn index_settings() -> IndexSettings {
+    IndexSettings {
+        sort_by_field: Some(IndexSortByField {
+            field: "timestamp".to_string(),
+            order: Order::Desc,
+        }),
+        ..Default::default()
+    }
+}
+
+fn open_index(schema: &Schema) -> Index {
+    let index_path = build_index_path();
+
+    if !index_path.exists() {
+        fs::create_dir_all(&index_path).unwrap();
+    }
+
+    let directory: Box<dyn Directory> = Box::new(MmapDirectory::open(index_path).unwrap());
+
+    let mut index_builder = Index::builder().schema(schema.clone());
+    index_builder = index_builder.settings(index_settings());
+
+    match index_builder.open_or_create(directory) {
+        Ok(index) => index,
+        // Written by an older version of fzh
+        Err(TantivyError::SchemaError(_)) => migrate_index(schema),
+        Err(e) => panic!("Unable to open the index: {}", e),
+    }
+}
+
+/// Rebuilds the index when its schema is from an older version of fzh. Every
+/// document is read back with the schema it was written with and indexed
+/// again with defaults for the fields it doesn't have. The old index is kept
+/// next to the new one in case anything goes wrong.
+fn migrate_index(schema: &Schema) -> Index {
+    let index_path = build_index_path();
+    let old_index = Index::open_in_dir(&index_path).unwrap();
+    let entries = migrated_entries(&old_index);
+    drop(old_index);
+
+    let backup_path = index_path.with_file_name(format!(".fzh-backup-{}", Ulid::new()));
+    fs::rename(&index_path, &backup_path).unwrap();
+    fs::create_dir_all(&index_path).unwrap();
+
+    let index = Index::builder()
+        .schema(schema.clone())
+        .settings(index_settings())
+        .create_in_dir(&index_path)
+        .unwrap();
+    // The migrated index has counted the same imports
+    fs::copy(import_marks_path(&backup_path), import_marks_path(&index_path)).ok();
+
+    let entry_count = entries.len();
+    reindex_entries(&index, entries);
+
+    eprintln!(
+        "Migrated {} commands in {:#?} to this version of fzh, the old index was kept in {:#?}.",
+        entry_count, index_path, backup_path
+    );
+
+    index
+}
+
+/// Every command in an index with an older schema, one per directory and
+/// command.
+fn migrated_entries(old_index: &Index) -> Vec<(HistoryEntry, CommandUses)> {
+    let old_schema = old_index.schema();
+    let searcher = build_searcher(old_index);
+    let mut entries: HashMap<u64, (HistoryEntry, CommandUses)> = HashMap::new();
+
+    for doc in find_documents(&searcher, &AllQuery) {
+        let entry = HistoryEntry::from_document(&doc, &old_schema);
+        let uses = CommandUses::from_document(&doc, &old_schema);
+        let id = command_id(&entry.directory, &entry.command);
+
+        match entries.get_mut(&id) {
+            // Older versions added a copy on every run, each carrying the
+            // counts so far
+            Some((latest_entry, latest_uses)) => {
+                latest_uses.runs = latest_uses.runs.max(uses.runs);
+                latest_uses.selections = latest_uses.selections.max(uses.selections);
+                latest_uses.frecency = latest_uses.frecency.max(uses.frecency);
+
+                if entry.timestamp > latest_entry.timestamp {
+                    *latest_entry = entry;
+                }
+            }
+            None => {
+                entries.insert(id, (entry, uses));
+            }
+        }
+    }
+
+    fold_unknown_directories(entries.into_values().collect(), frecency_half_life_ms())
+}
+
+/// Writes already counted entries to an empty index in one commit.
+fn reindex_entries(index: &Index, entries: Vec<(HistoryEntry, CommandUses)>) {
+    let schema = index.schema();
+    let mut index_writer = open_index_writer(index);
+    let searcher = build_searcher(index);
+
+    for (entry, uses) in entries {
+        index_command(entry, uses, &schema, &searcher, &index_writer);
+    }
+
+    index_writer.commit().unwrap();
+}
+
+/// Opens the writer once no other fzh process holds it, e.g. one recording a
+/// pick in the background. Open it before the searcher so the searcher sees
+/// everything the other process wrote.
+fn open_index_writer(index: &Index) -> IndexWriter {
+    for _ in 0..100 {
+        match index.writer(30_000_000) {
+            Err(TantivyError::LockFailure(..)) => std::thread::sleep(std::time::Duration::from_millis(50)),
+            index_writer => return index_writer.unwrap(),
+        }
+    }
+
+    index.writer(30_000_000).unwrap()
+}
+
+fn build_searcher(index: &Index) -> Searcher {
+    let reader = index
+        .reader_builder()
+        .reload_policy(ReloadPolicy::OnCommit)
+        .try_into()
+        .unwrap();
+
+    reader.searcher()
+}
+
+fn command_id(directory: &str, command: &str) -> u64 {
+    let combined_string = format!("{} {}", directory, command);
+
+    city::hash64(combined_string.as_str())
+}
+
+/// `/src/app` -> `["/src/app", "/src", "/"]`. Imports without a directory
+/// have no ancestors.
+fn directory_ancestors(directory: &str) -> Vec<String> {
+    if directory.is_empty() || directory == UNKNOWN_DIRECTORY {
+        return vec![];
+    }
+
+    Path::new(trim_directory(directory))
+        .ancestors()
+        .filter_map(|ancestor| ancestor.to_str())
+        .filter(|ancestor| !ancestor.is_empty())
+        .map(|ancestor| ancestor.to_string())
+        .collect()
+}
+
+/// Drops trailing slashes, other than the one in `/`.
+fn trim_directory(directory: &str) -> &str {
+    match directory.trim_end_matches('/') {
+        "" if directory.starts_with('/') => "/",
+        trimmed => trimmed,
+    }
+}
+
+/// Scores how close the directory a command was run in is to the current
+/// one: 1 for the same directory, shrinking with each directory between them
+/// when one is inside the other, and 0 for unrelated directories.
+fn directory_proximity(current_dir: &str, directory: &str) -> f32 {
+    if current_dir.is_empty() || directory.is_empty() || directory == UNKNOWN_DIRECTORY {
+        return 0.0;
+    }
+
+    let current_path = Path::new(current_dir);
+    let path = Path::new(directory);
+
+    if !current_path.starts_with(path) && !path.starts_with(current_path) {
+        return 0.0;
+    }
+
+    let distance = current_path.components().count().abs_diff(path.components().count());
+
+    1.0 / (1.0 + distance as f32)
+}
+
+/// Writes `entry` as the only live document for its directory and command.
+/// Any previous document is deleted and its counters are carried over, with
+/// `uses` added on top.
+///
+/// Imports without a directory share their identity with live copies of the
+/// command: an import is counted towards the latest live copy, and the next
+/// live run of an imported command takes over its counters.
+fn index_command(mut entry: HistoryEntry, uses: CommandUses, schema: &Schema, searcher: &Searcher, index_writer: &IndexWriter) {
+    let id_field = schema.get_field("id").unwrap();
+    let timestamp_field = schema.get_field("timestamp").unwrap();
+    let run_count_field = schema.get_field("run_count").unwrap();
+    let selected_count_field = schema.get_field("selected_count").unwrap();
+    let frecency_field = schema.get_field("frecency").unwrap();
+    let exit_code_field = schema.get_field("exit_code").unwrap();
+    let duration_ms_field = schema.get_field("duration_ms").unwrap();
+    let command_field = schema.get_field("command").unwrap();
+    let directory_field = schema.get_field("directory").unwrap();
+    let directory_ancestors_field = schema.get_field("directory_ancestors").unwrap();
+    let hostname_field = schema.get_field("hostname").unwrap();
+    let username_field = schema.get_field("username").unwrap();
+    let session_field = schema.get_field("session").unwrap();
+    let shell_field = schema.get_field("shell").unwrap();
+    let tty_field = schema.get_field("tty").unwrap();
+    let pid_field = schema.get_field("pid").unwrap();
+    let git_root_field = schema.get_field("git_root").unwrap();
+    let git_branch_field = schema.get_field("git_branch").unwrap();
+    let git_path_field = schema.get_field("git_path").unwrap();
+    let git_repository_field = schema.get_field("git_repository").unwrap();
+    let paths_field = schema.get_field("paths").unwrap();
+
+    let mut command_doc = Document::default();
+    let half_life_ms = frecency_half_life_ms();
+    let mut run_count = uses.runs;
+    let mut selected_count = uses.selections;
+    let mut frecency = uses.frecency;
+
+    if entry.directory == UNKNOWN_DIRECTORY {
+        let command_term = tantivy::Term::from_field_text(command_field, &entry.command);
+        let latest_live_doc = find_documents(searcher, &TermQuery::new(command_term, IndexRecordOption::Basic))
+            .into_iter()
+            .filter(|doc| stored_string(doc, directory_field) != UNKNOWN_DIRECTORY)
+            .max_by_key(|doc| stored_u64(doc, timestamp_field));
+
+        if let Some(live_doc) = latest_live_doc {
+            if stored_u64(&live_doc, timestamp_field) >= entry.timestamp {
+                frecency = decay_frecency(frecency, entry.timestamp, stored_u64(&live_doc, timestamp_field), half_life_ms);
+                entry = HistoryEntry::from_document(&live_doc, schema);
+            } else {
+                entry.directory = stored_string(&live_doc, directory_field);
+            }
+        }
+    } else {
+        let unknown_id_term = tantivy::Term::from_field_u64(id_field, command_id(UNKNOWN_DIRECTORY, &entry.command));
+        let unknown_docs = find_documents(searcher, &TermQuery::new(unknown_id_term.clone(), IndexRecordOption::Basic));
+
+        for unknown_doc in unknown_docs {
+            run_count += stored_u64(&unknown_doc, run_count_field);
+            selected_count += stored_u64(&unknown_doc, selected_count_field);
+            frecency += decay_frecency(
+                stored_f64(&unknown_doc, frecency_field),
+                stored_u64(&unknown_doc, timestamp_field),
+                entry.timestamp,
+                half_life_ms,
+            );
+        }
+
+        index_writer.delete_term(unknown_id_term);
+    }
+
+    let assigned_id = command_id(&entry.directory, &entry.command);
+    let id_term = tantivy::Term::from_field_u64(id_field, assigned_id);
+    let previous_docs = find_documents(searcher, &TermQuery::new(id_term.clone(), IndexRecordOption::Basic));
+
+    // An older run adds to the counters but keeps the latest run's details
+    if let Some(latest_doc) = previous_docs.iter().max_by_key(|doc| stored_u64(doc, timestamp_field)) {
+        let latest_timestamp = stored_u64(latest_doc, timestamp_field);
+
+        if latest_timestamp > entry.timestamp {
+            frecency = decay_frecency(frecency, entry.timestamp, latest_timestamp, half_life_ms);
+            entry = HistoryEntry::from_document(latest_doc, schema);
+        }
+    }
+
+    for previous_doc in previous_docs {
+        run_count += stored_u64(&previous_doc, run_count_field);
+        selected_count += stored_u64(&previous_doc, selected_count_field);
+        frecency += decay_frecency(
+            stored_f64(&previous_doc, frecency_field),
+            stored_u64(&previous_doc, timestamp_field),
+            entry.timestamp,
+            half_life_ms,
+        );
+    }
+
+    command_doc.add_u64(id_field, assigned_id);
+    command_doc.add_u64(timestamp_field, entry.timestamp);
+    command_doc.add_u64(run_count_field, run_count);
+    command_doc.add_u64(selected_count_field, selected_count);
+    command_doc.add_f64(frecency_field, frecency);
+    command_doc.add_u64(exit_code_field, entry.exit_code);
+    command_doc.add_u64(duration_ms_field, entry.duration_ms);
+    command_doc.add_text(command_field, entry.command);
+    for ancestor in directory_ancestors(&entry.directory) {
+        command_doc.add_text(directory_ancestors_field, ancestor);
+    }
+    command_doc.add_text(directory_field, entry.directory);
+    command_doc.add_text(hostname_field, entry.hostname);
+    command_doc.add_text(username_field, entry.username);
+    command_doc.add_text(session_field, entry.session);
+    command_doc.add_text(shell_field, entry.shell);
+    command_doc.add_text(tty_field, entry.tty);
+    command_doc.add_u64(pid_field, entry.pid);
+    command_doc.add_text(git_root_field, entry.git.root);
+    command_doc.add_text(git_branch_field, entry.git.branch);
+    command_doc.add_text(git_path_field, entry.git.relative_path);
+    command_doc.add_text(git_repository_field, entry.git.repository);
+    for path in entry.paths {
+        command_doc.add_text(paths_field, path);
+    }
+
+    // Deletes only apply to documents added before them, so this removes the
+    // old copies (and any from older versions of fzh) but not the new one.
+    index_writer.delete_term(id_term);
+    index_writer.add_document(command_doc).unwrap();
+}
+
+/// What an indexed command is being used for, added to its counters.
+struct CommandUses {
+    runs: u64,
+    /// Picks from the search client
+    selections: u64,
+    /// Runs as of the entry's timestamp, older ones decayed by
+    /// `decay_frecency`
+    frecency: f64,
+}
+
+impl CommandUses {
+    /// `runs` runs at the entry's timestamp
+    fn runs(runs: u64) -> CommandUses {
+        CommandUses {
+            runs,
+            selections: 0,
+            frecency: runs as f64,
+        }
+    }
+
+    /// Reads the counters back from an indexed document. Older versions of
+    /// fzh counted runs in `times_selected` and had no frecency, which starts
+    /// out as every run happening at the last one.
+    fn from_document(doc: &Document, schema: &Schema) -> CommandUses {
+        let runs = schema
+            .get_field("run_count")
+            .or_else(|| schema.get_field("times_selected"))
+            .map(|field| stored_u64(doc, field))
+            .unwrap_or(1);
+
+        CommandUses {
+            runs,
+            selections: schema.get_field("selected_count").map(|field| stored_u64(doc, field)).unwrap_or(0),
+            frecency: schema.get_field("frecency").map(|field| stored_f64(doc, field)).unwrap_or(runs as f64),
+        }
+    }
+}
+
+/// How long it takes a run to count for half as much, `half_life_days` in
+/// the config.
+fn frecency_half_life_ms() -> u64 {
+    (CONFIG.half_life_days * DAY_MS as f64) as u64
+}
+
+/// Moves a frecency from `from_ms` to `to_ms`, halving it every half-life.
+/// Moving backwards in time grows it instead.
+fn decay_frecency(frecency: f64, from_ms: u64, to_ms: u64, half_life_ms: u64) -> f64 {
+    let elapsed_ms = to_ms as f64 - from_ms as f64;
+
+    frecency * 2f64.powf(-elapsed_ms / half_life_ms as f64)
+}
+
+/// Counts a pick from the search client towards the copy of `command` run in
+/// `current_dir`, or the most recent copy when it was never run there.
+fn record_selection(index: &Index, command: &str, current_dir: &str) {
+    let schema = index.schema();
+    let mut index_writer = open_index_writer(index);
+    let searcher = build_searcher(index);
+    let command_field = schema.get_field("command").unwrap();
+    let directory_field = schema.get_field("directory").unwrap();
+    let timestamp_field = schema.get_field("timestamp").unwrap();
+
+    let command_term = tantivy::Term::from_field_text(command_field, command);
+    let selected_doc = find_documents(&searcher, &TermQuery::new(command_term, IndexRecordOption::Basic))
+        .into_iter()
+        .max_by_key(|doc| (stored_string(doc, directory_field) == current_dir, stored_u64(doc, timestamp_field)));
+
+    if let Some(selected_doc) = selected_doc {
+        let entry = HistoryEntry::from_document(&selected_doc, &schema);
+
+        let uses = CommandUses {
+            runs: 0,
+            selections: 1,
+            frecency: 0.0,
+        };
+
+        index_command(entry, uses, &schema, &searcher, &index_writer);
+        index_writer.commit().unwrap();
+    }
+}
+
+/// Every document matching `query`.
+fn find_documents(searcher: &Searcher, query: &dyn Query) -> Vec<Document> {
+    searcher
+        .search(query, &DocSetCollector)
+        .unwrap()
+        .into_iter()
+        .map(|doc_address| searcher.doc(doc_address).unwrap())
+        .collect()
+}
+
+fn interactive_search_command(
+    fd_path: String,
+    text: String,
+    mode: SearchMode,
+    scope: SearchScope,
+    session: String,
+    limit: Option<usize>,
+) -> std::io::Result<String> {
+    let theme = ColorfulTheme::default();
+    let selection = FuzzyHistorySelect::with_theme(&theme)
+        .with_active_item_style(theme.active_item_style.clone())
+        .with_initial_text(text.clone())
+        .with_mode(mode)
+        .with_scope(scope, session)
+        .with_limit(limit)
+        .set_items_from_search(text)
+        .default(0)
+        .interact_on_opt(fd_path, &Term::stderr());
+
+    selection
+}
+
+/// How the search text is turned into the query on the `command` field.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum SearchMode {
+    /// Every character in order with anything in between, matched literally
+    Fuzzy,
+    /// Commands that start with the search text
+    Prefix,
+    /// Commands that contain the search text
+    Substring,
+    /// Commands that contain the search text as whole words
+    Word,
+    /// The search text is used as a regular expression
+    Regex,
+}
+
+// Word characters for `SearchMode::Word`, kept to ASCII so the automaton
+// tantivy builds from the pattern stays small.
+const NON_WORD_CHAR: &str = "[^A-Za-z0-9_]";
+
+impl SearchMode {
+    /// The order ctrl-s cycles through
+    const ALL: [SearchMode; 5] = [
+        SearchMode::Fuzzy,
+        SearchMode::Prefix,
+        SearchMode::Substring,
+        SearchMode::Word,
+        SearchMode::Regex,
+    ];
+
+    fn from_name(name: &str) -> Option<SearchMode> {
+        SearchMode::ALL.into_iter().find(|mode| mode.name() == name)
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            SearchMode::Fuzzy => "fuzzy",
+            SearchMode::Prefix => "prefix",
+            SearchMode::Substring => "substring",
+            SearchMode::Word => "word",
+            SearchMode::Regex => "regex",
+        }
+    }
+
+    fn next(&self) -> SearchMode {
+        let position = SearchMode::ALL.iter().position(|mode| mode == self).unwrap();
+
+        SearchMode::ALL[(position + 1) % SearchMode::ALL.len()]
+    }
+
+    /// Builds the pattern for `RegexQuery`, which has to match the whole
+    /// command. `(?s)` lets `.` match the newlines in multi-line commands.
+    fn pattern(&self, text: &str) -> String {
+        match self {
+            SearchMode::Fuzzy => {
+                let text_parts: Vec<String> = text.chars().map(|c| regex::escape(&c.to_string())).collect();
+                format!("(?s){}", ["", text_parts.join(".*").as_str(), ""].join(".*"))
+            }
+            SearchMode::Prefix => format!("(?s){}.*", regex::escape(text)),
+            SearchMode::Substring => format!("(?s).*{}.*", regex::escape(text)),
+            SearchMode::Word => format!(
+                "(?s)(.*{non_word})?{}({non_word}.*)?",
+                regex::escape(text),
+                non_word = NON_WORD_CHAR
+            ),
+            SearchMode::Regex => format!("(?s).*(?:{}).*", text),
+        }
+    }
+
+    /// The regex that finds the part of a command to highlight for modes that
+    /// don't match with skim, see `highlight_range`.
+    fn highlight_regex(&self, search_term: &str) -> Option<Regex> {
+        if search_term.is_empty() {
+            return None;
+        }
+
+        let pattern = match self {
+            SearchMode::Fuzzy => return None,
+            SearchMode::Prefix => format!("^(?P<m>{})", regex::escape(search_term)),
+            SearchMode::Substring => format!("(?P<m>{})", regex::escape(search_term)),
+            SearchMode::Word => format!(
+                "(?:^|{non_word})(?P<m>{})(?:{non_word}|$)",
+                regex::escape(search_term),
+                non_word = NON_WORD_CHAR
+            ),
+            SearchMode::Regex => format!("(?P<m>{})", search_term),
+        };
+
+        Regex::new(&pattern).ok()
+    }
+}
+
+/// The part of `text` a `SearchMode::highlight_regex` matched, as a byte range.
+fn highlight_range(highlight_regex: &Regex, text: &str) -> Option<(usize, usize)> {
+    let matched = highlight_regex.captures(text)?.name("m")?;
+
+    if matched.start() == matched.end() {
+        return None;
+    }
+
+    Some((matched.start(), matched.end()))
+}
+
+const DAY_MS: u64 = 24 * 60 * 60 * 1000;
+
+/// A `key:value` token from the search text that narrows results
+/// instead of being matched against the command.
+#[derive(Debug, PartialEq)]
+enum QueryFilter {
+    /// `dir:<path>`, the directory or anything below it
+    Directory(String),
+    /// `exit:<code>`
+    ExitCode(u64),
+    /// `exit:!<code>`, only commands with a known exit code
+    NotExitCode(u64),
+    /// `after:<time>`, in milliseconds
+    After(u64),
+    /// `before:<time>`, in milliseconds
+    Before(u64),
+    /// `host:<hostname>`
+    Host(String),
+    /// `not:<word>`, commands that don't contain it
+    Exclude(String),
+}
+
+impl QueryFilter {
+    fn clauses(&self, schema: &Schema) -> Vec<(Occur, Box<dyn Query>)> {
+        let exit_code_field = schema.get_field("exit_code").unwrap();
+        let timestamp_field = schema.get_field("timestamp").unwrap();
+
+        let exit_code_query = |exit_code: u64| -> Box<dyn Query> {
+            Box::new(TermQuery::new(
+                tantivy::Term::from_field_u64(exit_code_field, exit_code),
+                IndexRecordOption::Basic,
+            ))
+        };
+
+        match self {
+            QueryFilter::Directory(directory) => {
+                let directory_ancestors_field = schema.get_field("directory_ancestors").unwrap();
+                let directory_term = tantivy::Term::from_field_text(directory_ancestors_field, trim_directory(directory));
+
+                vec![(Occur::Must, Box::new(TermQuery::new(directory_term, IndexRecordOption::Basic)))]
+            }
+            QueryFilter::ExitCode(exit_code) => vec![(Occur::Must, exit_code_query(*exit_code))],
+            QueryFilter::NotExitCode(exit_code) => vec![
+                (Occur::MustNot, exit_code_query(*exit_code)),
+                (Occur::MustNot, exit_code_query(UNKNOWN_EXIT_CODE)),
+            ],
+            QueryFilter::After(timestamp) => vec![(
+                Occur::Must,
+                Box::new(RangeQuery::new_u64(timestamp_field, *timestamp..u64::MAX)),
+            )],
+            QueryFilter::Before(timestamp) => vec![(
+                Occur::Must,
+                Box::new(RangeQuery::new_u64(timestamp_field, 0..*timestamp)),
+            )],
+            QueryFilter::Host(hostname) => {
+                let hostname_field = schema.get_field("hostname").unwrap();
+                let hostname_term = tantivy::Term::from_field_text(hostname_field, hostname);
+
+                vec![(Occur::Must, Box::new(TermQuery::new(hostname_term, IndexRecordOption::Basic)))]
+            }
+            QueryFilter::Exclude(word) => {
+                let command_field = schema.get_field("command").unwrap();
+                let pattern = format!("(?s).*{}.*", regex::escape(word));
+
+                match RegexQuery::from_pattern(&pattern, command_field) {
+                    Ok(word_query) => vec![(Occur::MustNot, Box::new(word_query))],
+                    Err(_) => vec![],
+                }
+            }
+        }
+    }
+}
+
+struct ParsedQuery {
+    /// The search text left once filters are taken out
+    text: String,
+    filters: Vec<QueryFilter>,
+}
+
+/// Splits filter tokens out of the search text. Anything that isn't a valid
+/// filter, like `http://example.com` or `exit:abc`, stays part of the text.
+fn parse_query(query: &str, current_ms: u64) -> ParsedQuery {
+    let mut text_parts = vec![];
+    let mut filters = vec![];
+
+    for token in query.split(' ') {
+        match parse_query_filter(token, current_ms) {
+            Some(filter) => filters.push(filter),
+            None => text_parts.push(token),
+        }
+    }
+
+    ParsedQuery {
+        text: text_parts.join(" "),
+        filters,
+    }
+}
+
+fn parse_query_filter(token: &str, current_ms: u64) -> Option<QueryFilter> {
+    let (key, value) = token.split_once(':')?;
+
+    if value.is_empty() {
+        return None;
+    }
+
+    match key {
+        "dir" => {
+            let directory = match value.strip_prefix('~') {
+                Some(relative_path) => format!("{}{}", home_dir()?.to_str()?, relative_path),
+                None => value.to_string(),
+            };
+
+            Some(QueryFilter::Directory(directory))
+        }
+        "exit" => match value.strip_prefix('!') {
+            Some(exit_code) => exit_code.parse::<u64>().ok().map(QueryFilter::NotExitCode),
+            None => value.parse::<u64>().ok().map(QueryFilter::ExitCode),
+        },
+        "after" => parse_query_time(value, current_ms).map(QueryFilter::After),
+        "before" => parse_query_time(value, current_ms).map(QueryFilter::Before),
+        "host" => Some(QueryFilter::Host(value.to_string())),
+        "not" => Some(QueryFilter::Exclude(value.to_string())),
+        _ => None,
+    }
+}
+
+/// Parses `today`, `yesterday`, a relative age like `3h`, `2d` or `1w`, or a
+/// `YYYY-MM-DD` date. Days start at midnight UTC.
+fn parse_query_time(value: &str, current_ms: u64) -> Option<u64> {
+    lazy_static! {
+        static ref AGE_RE: Regex = Regex::new(r"^(?P<amount>\d+)(?P<unit>[hdw])$").unwrap();
+        static ref DATE_RE: Regex = Regex::new(r"^(?P<year>\d{4})-(?P<month>\d{2})-(?P<day>\d{2})$").unwrap();
+    }
+
+    let today_ms = current_ms - current_ms % DAY_MS;
+
+    if value == "today" {
+        return Some(today_ms);
+    }
+
+    if value == "yesterday" {
+        return Some(today_ms.saturating_sub(DAY_MS));
+    }
+
+    if let Some(captures) = AGE_RE.captures(value) {
+        let amount = captures.name("amount").unwrap().as_str().parse::<u64>().ok()?;
+        let unit_ms = match captures.name("unit").unwrap().as_str() {
+            "h" => DAY_MS / 24,
+            "d" => DAY_MS,
+            _ => 7 * DAY_MS,
+        };
+
+        return Some(current_ms.saturating_sub(amount.saturating_mul(unit_ms)));
+    }
+
+    let captures = DATE_RE.captures(value)?;
+    let year = captures.name("year").unwrap().as_str().parse::<i64>().ok()?;
+    let month = captures.name("month").unwrap().as_str().parse::<i64>().ok()?;
+    let day = captures.name("day").unwrap().as_str().parse::<i64>().ok()?;
+
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+
+    // Days since the unix epoch for a proleptic Gregorian date, from
+    // http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146097 + day_of_era - 719468;
+
+    u64::try_from(days).ok().map(|days| days * DAY_MS)
+}
+
+/// Limits results to where, or when, a command was run.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum SearchScope {
+    /// Everything
+    Global,
+    /// Commands run in the current directory
+    Directory,
+    /// Commands run in the current directory or anywhere below it
+    Subtree,
+    /// Commands run anywhere in the current git repository, or the subtree
+    /// outside of one
+    Repository,
+    /// Commands run in the current shell session
+    Session,
+}
+
+impl SearchScope {
+    /// The order ctrl-f cycles through
+    const ALL: [SearchScope; 5] = [
+        SearchScope::Global,
+        SearchScope::Directory,
+        SearchScope::Subtree,
+        SearchScope::Repository,
+        SearchScope::Session,
+    ];
+
+    fn from_name(name: &str) -> Option<SearchScope> {
+        SearchScope::ALL.into_iter().find(|scope| scope.name() == name)
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            SearchScope::Global => "global",
+            SearchScope::Directory => "directory",
+            SearchScope::Subtree => "subtree",
+            SearchScope::Repository => "repo",
+            SearchScope::Session => "session",
+        }
+    }
+
+    /// The next scope, skipping the session scope when the shell didn't pass a
+    /// session.
+    fn next(&self, has_session: bool) -> SearchScope {
+        let position = SearchScope::ALL.iter().position(|scope| scope == self).unwrap();
+        let next_scope = SearchScope::ALL[(position + 1) % SearchScope::ALL.len()];
+
+        if next_scope == SearchScope::Session && !has_session {
+            next_scope.next(has_session)
+        } else {
+            next_scope
+        }
+    }
+
+    fn clauses(&self, schema: &Schema, context: &SearchContext) -> Vec<(Occur, Box<dyn Query>)> {
+        match self {
+            SearchScope::Global => vec![],
+            SearchScope::Directory => {
+                let directory_field = schema.get_field("directory").unwrap();
+                let directory_term = tantivy::Term::from_field_text(directory_field, &context.current_dir);
+
+                vec![(Occur::Must, Box::new(TermQuery::new(directory_term, IndexRecordOption::Basic)))]
+            }
+            SearchScope::Subtree => QueryFilter::Directory(context.current_dir.clone()).clauses(schema),
+            SearchScope::Repository => {
+                let root = find_git_root(Path::new(&context.current_dir))
+                    .and_then(|root| root.to_str().map(|root| root.to_string()))
+                    .unwrap_or(context.current_dir.clone());
+
+                if context.repository.is_empty() {
+                    return QueryFilter::Directory(root).clauses(schema);
+                }
+
+                // Other clones and worktrees of the repository too
+                let git_repository_field = schema.get_field("git_repository").unwrap();
+                let repository_term = tantivy::Term::from_field_text(git_repository_field, &context.repository);
+                let mut repository_clauses: Vec<(Occur, Box<dyn Query>)> =
+                    vec![(Occur::Should, Box::new(TermQuery::new(repository_term, IndexRecordOption::Basic)))];
+
+                for (_, directory_query) in QueryFilter::Directory(root).clauses(schema) {
+                    repository_clauses.push((Occur::Should, directory_query));
+                }
+
+                vec![(Occur::Must, Box::new(BooleanQuery::new(repository_clauses)))]
+            }
+            // Without a session everything from imports (which have none)
+            // would match
+            SearchScope::Session if context.session.is_empty() => vec![],
+            SearchScope::Session => {
+                let session_field = schema.get_field("session").unwrap();
+                let session_term = tantivy::Term::from_field_text(session_field, &context.session);
+
+                vec![(Occur::Must, Box::new(TermQuery::new(session_term, IndexRecordOption::Basic)))]
+            }
+        }
+    }
+}
+
+/// The closest directory at or above `directory` with a `.git` directory, or a
+/// `.git` file for worktrees and submodules.
+fn find_git_root(directory: &Path) -> Option<std::path::PathBuf> {
+    directory
+        .ancestors()
+        .find(|ancestor| ancestor.join(".git").exists())
+        .map(|ancestor| ancestor.to_path_buf())
+}
+
+/// Where a command was run in a git work tree.
+#[derive(Clone, Debug, Default, PartialEq)]
+struct GitContext {
+    root: String,
+    /// The checked out branch, or the commit when `HEAD` is detached
+    branch: String,
+    /// The directory relative to `root`, empty at the root
+    relative_path: String,
+    /// Identifies the repository across clones and worktrees: the `origin`
+    /// remote as `host/path`, or the shared git directory without one
+    repository: String,
+}
+
+/// Reads the git context of `directory` from the files in `.git`, without
+/// running `git`.
+fn git_context(directory: &Path) -> Option<GitContext> {
+    let root = find_git_root(directory)?;
+    let dot_git = root.join(".git");
+
+    // Worktrees and submodules have a `.git` file pointing at their git
+    // directory, e.g. `gitdir: /src/app/.git/worktrees/feature`
+    let git_dir = if dot_git.is_file() {
+        let contents = fs::read_to_string(&dot_git).ok()?;
+        root.join(contents.trim().strip_prefix("gitdir:")?.trim())
+    } else {
+        dot_git
+    };
+
+    // Worktrees share the config and refs of the repository they came from
+    let common_dir = match fs::read_to_string(git_dir.join("commondir")) {
+        Ok(common_dir) => git_dir.join(common_dir.trim()),
+        Err(_) => git_dir.clone(),
+    };
+
+    let head = fs::read_to_string(git_dir.join("HEAD")).unwrap_or_default();
+    let branch = match head.trim().strip_prefix("ref:") {
+        Some(reference) => reference.trim().trim_start_matches("refs/heads/").to_string(),
+        None => head.trim().to_string(),
+    };
 
-    let mut current_times_selected = 0;
+    let repository = fs::read_to_string(common_dir.join("config"))
+        .ok()
+        .and_then(|config| origin_url(&config))
+        .unwrap_or_else(|| {
+            common_dir
+                .canonicalize()
+                .unwrap_or(common_dir)
+                .to_str()
+                .unwrap_or("")
+                .to_string()
+        });
+
+    Some(GitContext {
+        root: root.to_str()?.to_string(),
+        branch,
+        relative_path: directory.strip_prefix(&root).ok()?.to_str()?.to_string(),
+        repository,
+    })
+}
 
-    for (_score, doc_address) in top_docs {
-        let retrieved_doc = searcher.doc(doc_address).unwrap();
+/// The `url` of `[remote "origin"]` in a git config file.
+fn origin_url(config: &str) -> Option<String> {
+    let mut in_origin = false;
 
-        current_times_selected = retrieved_doc
-            .get_first(times_selected_field)
-            .unwrap()
-            .as_u64()
-            .unwrap();
+    for line in config.lines() {
+        let line = line.trim();
 
-        // println!("{}", schema.to_json(&retrieved_doc));
+        if line.starts_with('[') {
+            in_origin = line == "[remote \"origin\"]";
+        } else if in_origin {
+            if let Some(("url", url)) = line.split_once('=').map(|(key, value)| (key.trim(), value.trim())) {
+                return Some(normalize_remote_url(url));
+            }
+        }
     }
 
-    let times_selected = current_times_selected + 1;
-    //end
+    None
+}
+
+/// Reduces a remote URL to `host/path`, so the SSH and HTTPS URLs of a
+/// repository match, e.g. `git@github.com:o/r.git` and `https://github.com/o/r`
+/// are both `github.com/o/r`.
+fn normalize_remote_url(url: &str) -> String {
+    let (host, path) = match url.split_once("://") {
+        Some((_, address)) => address.split_once('/').unwrap_or((address, "")),
+        // scp-like `[user@]host:path`, anything else is a local path
+        None => match url.split_once(':') {
+            Some((host, path)) if !host.contains('/') => (host, path),
+            _ => {
+                let path = url.trim_end_matches('/');
+                return path.strip_suffix(".git").unwrap_or(path).to_string();
+            }
+        },
+    };
 
-    command_doc.add_u64(id_field, assigned_id);
-    command_doc.add_u64(timestamp_field, Ulid::new().timestamp_ms());
-    command_doc.add_u64(times_selected_field, times_selected);
-    command_doc.add_u64(exit_code_field, exit_code);
-    command_doc.add_text(command_field, new_command);
-    command_doc.add_text(directory_field, command_directory);
+    let host = host.rsplit_once('@').map_or(host, |(_, host)| host);
+    let host = host.split(':').next().unwrap_or(host);
+    let path = path.trim_matches('/');
+    let path = path.strip_suffix(".git").unwrap_or(path);
 
-    command_doc
+    format!("{}/{}", host.to_lowercase(), path)
 }
 
-fn interactive_search_command(fd_path: String, text: String) -> std::io::Result<String> {
-    let results = search_command(text.clone());
-    let selection = FuzzyHistorySelect::with_theme(&ColorfulTheme::default())
-        .with_initial_text(text)
-        .items(&results)
-        .default(0)
-        .interact_on_opt(fd_path, &Term::stderr());
+/// Where and when a search is run from.
+struct SearchContext {
+    current_dir: String,
+    /// Milliseconds since the unix epoch
+    current_ms: u64,
+    session: String,
+    /// The git repository of `current_dir`, see `GitContext::repository`
+    repository: String,
+    half_life_ms: u64,
+    instant_failure_ms: u64,
+    weights: RankingWeights,
+}
 
-    selection
+/// One page of matching commands, best first.
+struct SearchResults {
+    commands: Vec<String>,
+    /// How long the last run of each command took, alongside `commands`
+    durations: Vec<u64>,
+    /// How many commands matched in total, across every page
+    total: usize,
 }
 
-fn search_command(text: String) -> Vec<String> {
+fn search_command(
+    text: String,
+    mode: SearchMode,
+    scope: SearchScope,
+    session: &str,
+    offset: usize,
+    limit: usize,
+) -> SearchResults {
     let schema = build_schema();
-    let index_path = build_index_path();
-    let directory: Box<dyn Directory> = Box::new(MmapDirectory::open(index_path).unwrap());
-
-    let settings = IndexSettings {
-        sort_by_field: Some(IndexSortByField {
-            field: "timestamp".to_string(),
-            order: Order::Desc,
-        }),
-        ..Default::default()
+    let index = open_index(&schema);
+
+    let current_dir = std::env::current_dir().unwrap();
+    let context = SearchContext {
+        current_dir: current_dir.to_str().unwrap().to_string(),
+        current_ms: Ulid::new().timestamp_ms(),
+        session: session.to_string(),
+        repository: git_context(&current_dir).map(|git| git.repository).unwrap_or_default(),
+        half_life_ms: frecency_half_life_ms(),
+        instant_failure_ms: CONFIG.instant_failure_ms,
+        weights: CONFIG.weights.clone(),
     };
-    let mut index_builder = Index::builder().schema(schema.clone());
-    index_builder = index_builder.settings(settings);
-    let index = index_builder.open_or_create(directory).unwrap();
-    // let index = Index::open_or_create(directory, schema.clone()).unwrap();
-    let reader = index
-        .reader_builder()
-        .reload_policy(ReloadPolicy::OnCommit)
-        .try_into()
-        .unwrap();
 
-    let searcher = reader.searcher();
-    let id_field = schema.get_field("id").unwrap();
+    search_index(&index, text, mode, scope, &context, offset, limit)
+}
+
+/// Returns up to `limit` of the best matching commands after skipping the
+/// first `offset`.
+fn search_index(
+    index: &Index,
+    text: String,
+    mode: SearchMode,
+    scope: SearchScope,
+    context: &SearchContext,
+    offset: usize,
+    limit: usize,
+) -> SearchResults {
+    let current_dir = context.current_dir.as_str();
+    let current_ms = context.current_ms;
+
+    let schema = index.schema();
+    let searcher = build_searcher(index);
     let timestamp_field = schema.get_field("timestamp").unwrap();
-    let times_selected_field = schema.get_field("times_selected").unwrap();
+    let frecency_field = schema.get_field("frecency").unwrap();
+    let selected_count_field = schema.get_field("selected_count").unwrap();
     let exit_code_field = schema.get_field("exit_code").unwrap();
+    let duration_ms_field = schema.get_field("duration_ms").unwrap();
     let directory_field = schema.get_field("directory").unwrap();
     let command_field = schema.get_field("command").unwrap();
 
-    let directory_term = tantivy::Term::from_field_text(
-        directory_field,
-        std::env::current_dir().unwrap().to_str().unwrap(),
-    );
-    let directory_query = TermQuery::new(directory_term, IndexRecordOption::Basic);
+    let parsed_query = parse_query(&text, current_ms);
+    let text = parsed_query.text;
+
+    let pattern = mode.pattern(&text);
+    let command_query = match RegexQuery::from_pattern(pattern.as_str(), command_field) {
+        Ok(command_query) => command_query,
+        // A regex that's still being typed, e.g. `(foo`
+        Err(_) => {
+            return SearchResults {
+                commands: vec![],
+                durations: vec![],
+                total: 0,
+            }
+        }
+    };
+
+    let mut clauses: Vec<(Occur, Box<dyn Query>)> = vec![(Occur::Must, Box::new(command_query))];
+
+    for filter in &parsed_query.filters {
+        clauses.extend(filter.clauses(&schema));
+    }
 
-    // let text_parts: Vec<&str> = text.split(" ").collect();
-    let text_parts: Vec<String> = text.chars().map(|c| c.to_string()).collect();
-    let pattern = ["", text_parts.join(".*").as_str(), ""].join(".*");
-    let command_query = RegexQuery::from_pattern(pattern.as_str(), command_field).unwrap();
+    clauses.extend(scope.clauses(&schema, context));
 
-    let query = BooleanQuery::new(vec![
-        (Occur::Should, Box::new(directory_query)),
-        (Occur::Must, Box::new(command_query)),
-    ]);
+    let query = BooleanQuery::new(clauses);
 
-    let one_month_ms: u64 = 2629800000;
-    let current_ms = Ulid::new().timestamp_ms();
+    let half_life_ms = context.half_life_ms;
+    let instant_failure_ms = context.instant_failure_ms;
+    let current_dir = current_dir.to_string();
+    let repository = context.repository.clone();
+    let session = context.session.clone();
+    let weights = context.weights.clone();
+    let git_repository_field = schema.get_field("git_repository").unwrap();
+    let session_field = schema.get_field("session").unwrap();
 
-    let (top_docs, _count) = searcher
+    let (top_docs, total) = searcher
         .search(
             &query,
             &(
-                TopDocs::with_limit(10).tweak_score(move |segment_reader: &SegmentReader| {
+                // `TopDocs` panics on a limit of 0
+                TopDocs::with_limit(limit.max(1)).and_offset(offset).tweak_score(move |segment_reader: &SegmentReader| {
                     let timestamp_reader = segment_reader.fast_fields().u64(timestamp_field).unwrap();
-                    let times_selected_reader = segment_reader.fast_fields().u64(times_selected_field).unwrap();
+                    let frecency_reader = segment_reader.fast_fields().f64(frecency_field).unwrap();
+                    let selected_count_reader = segment_reader.fast_fields().u64(selected_count_field).unwrap();
                     let exit_code_reader = segment_reader.fast_fields().u64(exit_code_field).unwrap();
-
-                    move |doc: DocId, original_score: Score| {
-                        // timestamp
-                        let ms_diff = current_ms - timestamp_reader.get_val(0);
-
-                        let decay: f64 = ms_diff as f64 / one_month_ms as f64;
-                        let timestamp_score_scaling = 1 as f64 - decay;
-                        let timestamp_score_boost = 1 as f32 * timestamp_score_scaling as f32;
-
-                        // times selected
-                        let times_selected = times_selected_reader.get_val(0);
-                        let mut time_selected_boost = times_selected as f32 / 100.0;
-
-                        if time_selected_boost > 1.0 {
-                            time_selected_boost = 1.0;
-                        }
+                    let duration_ms_reader = segment_reader.fast_fields().u64(duration_ms_field).unwrap();
+                    // Only the candidates that matched the query are read, and
+                    // they're sorted by time so a small block cache is enough.
+                    let store_reader = segment_reader.get_store_reader(10).unwrap();
+
+                    let matcher = fuzzy_matcher::skim::SkimMatcherV2::default();
+                    // Skim scores are meaningless against a regex
+                    let text = if mode == SearchMode::Fuzzy { text.clone() } else { "".to_string() };
+
+                    let current_dir = current_dir.clone();
+                    let repository = repository.clone();
+                    let session = session.clone();
+                    let weights = weights.clone();
+
+                    // The query's own score is left out, every signal is one
+                    // of the weighted boosts below
+                    move |doc: DocId, _original_score: Score| {
+                        // fuzzy score
+                        let retrieved_doc = store_reader.get(doc).unwrap();
+                        let command = retrieved_doc
+                            .get_first(command_field)
+                            .and_then(|value| value.as_text())
+                            .unwrap_or("");
+                        let fuzzy_score_boost = weights.fuzzy * fuzzy_score(&matcher, command, &text);
+
+                        // directory proximity
+                        let directory = retrieved_doc
+                            .get_first(directory_field)
+                            .and_then(|value| value.as_text())
+                            .unwrap_or("");
+                        let directory_boost = weights.directory * directory_proximity(&current_dir, directory);
+
+                        // same repository, wherever it's checked out
+                        let doc_repository = retrieved_doc
+                            .get_first(git_repository_field)
+                            .and_then(|value| value.as_text())
+                            .unwrap_or("");
+                        let same_repository = !repository.is_empty() && doc_repository == repository;
+                        let repository_boost = if same_repository { weights.repo } else { 0.0 };
+
+                        // same shell session
+                        let doc_session = retrieved_doc
+                            .get_first(session_field)
+                            .and_then(|value| value.as_text())
+                            .unwrap_or("");
+                        let same_session = !session.is_empty() && doc_session == session;
+                        let session_boost = if same_session { weights.session } else { 0.0 };
+
+                        // recency, the last run halving every half-life
+                        let timestamp = timestamp_reader.get_val(doc);
+                        let now = current_ms.max(timestamp);
+                        let recency_boost = weights.recency * decay_frecency(1.0, timestamp, now, half_life_ms) as f32;
+
+                        // frequency, every run decayed the same way. Heavily
+                        // used commands approach 1 and one-offs from long ago
+                        // fade to 0.
+                        let frecency = decay_frecency(frecency_reader.get_val(doc), timestamp, now, half_life_ms);
+                        let frequency_boost = weights.frequency * (frecency / (frecency + 1.0)) as f32;
+
+                        // times picked from the search client, a stronger
+                        // signal than running it
+                        let selected_count = selected_count_reader.get_val(doc);
+                        let selected_count_boost = weights.selection * (selected_count as f32 / 20.0).min(1.0);
 
                         // exit code boost
-                        let exit_code = exit_code_reader.get_val(0);
+                        let exit_code = exit_code_reader.get_val(doc);
                         let mut exit_code_boost = 0.0 as f32;
 
                         if exit_code == 0 {
-                            exit_code_boost = 1.0;
+                            exit_code_boost = weights.exit_code;
+                        }
+
+                        // Typos and missing commands fail almost instantly,
+                        // e.g. `gti status` exiting 127 after 5ms
+                        let duration_ms = duration_ms_reader.get_val(doc);
+
+                        if exit_code != 0 && exit_code != UNKNOWN_EXIT_CODE && duration_ms < instant_failure_ms {
+                            exit_code_boost = -weights.exit_code;
                         }
 
-                        timestamp_score_boost + time_selected_boost + exit_code_boost + original_score
+                        let score = fuzzy_score_boost
+                            + recency_boost
+                            + frequency_boost
+                            + selected_count_boost
+                            + exit_code_boost
+                            + directory_boost
+                            + repository_boost
+                            + session_boost;
+
+                        // Shorter commands break exact ties only. Skim scores
+                        // `gst` the same against `git status` and `git stash
+                        // list`, so that's what puts `git status` first when
+                        // nothing else tells them apart.
+                        (score, std::cmp::Reverse(command.chars().count()))
                     }
                 }),
                 Count,
@@ -415,9 +2400,10 @@ fn search_command(text: String) -> Vec<String> {
         )
         .unwrap();
 
-    let mut results = vec![];
+    let mut commands = vec![];
+    let mut durations = vec![];
 
-    for (_score, doc_address) in top_docs {
+    for (_score, doc_address) in top_docs.into_iter().take(limit) {
         let retrieved_doc = searcher.doc(doc_address).unwrap();
         let command = retrieved_doc
             .get_first(command_field)
@@ -426,11 +2412,32 @@ fn search_command(text: String) -> Vec<String> {
             .unwrap()
             .to_string();
 
-        results.push(command);
+        commands.push(command);
+        durations.push(stored_u64(&retrieved_doc, duration_ms_field));
         // println!("{}", schema.to_json(&retrieved_doc));
     }
 
-    results
+    SearchResults {
+        commands,
+        durations,
+        total,
+    }
+}
+
+/// Scores how well `command` matches `text` with the skim matcher, which
+/// rewards contiguous runs, word boundaries and prefixes, scaled to roughly
+/// 0-1 against `text` matching itself.
+fn fuzzy_score(matcher: &fuzzy_matcher::skim::SkimMatcherV2, command: &str, text: &str) -> f32 {
+    let best_score = match matcher.fuzzy_match(text, text) {
+        Some(best_score) if best_score > 0 => best_score,
+        _ => return 0.0,
+    };
+    let score = match matcher.fuzzy_match(command, text) {
+        Some(score) if score > 0 => score,
+        _ => return 0.0,
+    };
+
+    (score as f32 / best_score as f32).min(1.0)
 }
 
 pub struct FuzzyHistorySelect<'a> {
@@ -445,8 +2452,28 @@ pub struct FuzzyHistorySelect<'a> {
     /// Search string that a fuzzy search with start with.
     /// Defaults to an empty string.
     initial_text: String,
+    mode: SearchMode,
+    scope: SearchScope,
+    session: String,
+    /// The most results to load, `None` loads them all
+    limit: Option<usize>,
+    /// The text `items` were searched with, used to load the next page
+    query: String,
+    /// How many commands matched `query`
+    total: usize,
+    /// How long the last run of each item took, alongside `items`
+    durations: Vec<u64>,
+    /// Whether durations are shown after each item, toggled with ctrl-t
+    show_durations: bool,
+    /// The theme's style for the selected item, reapplied around highlighted
+    /// matches
+    active_item_style: console::Style,
 }
 
+/// How many results are loaded at a time. Scrolling past the last loaded
+/// result loads the next page.
+const SEARCH_PAGE_SIZE: usize = 50;
+
 impl Default for FuzzyHistorySelect<'static> {
     fn default() -> Self {
         Self::new()
@@ -490,8 +2517,11 @@ impl FuzzyHistorySelect<'_> {
     }
 
     pub fn set_items_from_search(&mut self, query: String) -> &mut Self {
-        let new_results = search_command(query);
-        self.items = new_results.iter().map(|i| i.to_string()).collect();
+        let new_results = search_command(query.clone(), self.mode, self.scope, &self.session, 0, self.page_size(0));
+        self.items = new_results.commands;
+        self.durations = new_results.durations;
+        self.total = new_results.total;
+        self.query = query;
 
         // for item in items {
         //     self.items.push(item.to_string());
@@ -499,12 +2529,91 @@ impl FuzzyHistorySelect<'_> {
         self
     }
 
+    /// How many results can be shown, `total` capped by `limit`.
+    fn available(&self) -> usize {
+        self.limit.map_or(self.total, |limit| limit.min(self.total))
+    }
+
+    /// The size of the page starting at `offset`, which is cut short by `limit`.
+    fn page_size(&self, offset: usize) -> usize {
+        self.limit
+            .map_or(SEARCH_PAGE_SIZE, |limit| limit.saturating_sub(offset).min(SEARCH_PAGE_SIZE))
+    }
+
+    /// Appends the next page of results, if there is one.
+    fn load_next_page(&mut self) {
+        let offset = self.items.len();
+
+        if offset >= self.available() {
+            return;
+        }
+
+        let next_results = search_command(
+            self.query.clone(),
+            self.mode,
+            self.scope,
+            &self.session,
+            offset,
+            self.page_size(offset),
+        );
+        self.items.extend(next_results.commands);
+        self.durations.extend(next_results.durations);
+    }
+
+    /// Sets the most results to show, `None` shows them all.
+    fn with_limit(&mut self, limit: Option<usize>) -> &mut Self {
+        self.limit = limit;
+        self
+    }
+
     /// Sets the search text that a fuzzy search starts with.
     pub fn with_initial_text<S: Into<String>>(&mut self, initial_text: S) -> &mut Self {
         self.initial_text = initial_text.into();
         self
     }
 
+    /// The prompt with the active search mode, scope and the selected result,
+    /// e.g. `[fuzzy] [global] [1/250]`.
+    fn status_prompt(&self, sel: Option<usize>) -> String {
+        let position = match sel {
+            Some(sel) if !self.items.is_empty() => sel + 1,
+            _ => 0,
+        };
+        let status = format!(
+            "[{}] [{}] [{}/{}]",
+            self.mode.name(),
+            self.scope.name(),
+            position,
+            self.available()
+        );
+
+        if self.prompt.is_empty() {
+            status
+        } else {
+            format!("{} {}", self.prompt, status)
+        }
+    }
+
+    /// Sets the style the theme gives the selected item.
+    fn with_active_item_style(&mut self, active_item_style: console::Style) -> &mut Self {
+        self.active_item_style = active_item_style;
+        self
+    }
+
+    /// Sets how the search text is matched against commands.
+    fn with_mode(&mut self, mode: SearchMode) -> &mut Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Sets which commands are searched, and the shell session used by the
+    /// session scope.
+    fn with_scope(&mut self, scope: SearchScope, session: String) -> &mut Self {
+        self.scope = scope;
+        self.session = session;
+        self
+    }
+
     /// Prefaces the menu with a prompt.
     ///
     /// When a prompt is set the system also prints out a confirmation after
@@ -591,7 +2700,7 @@ impl FuzzyHistorySelect<'_> {
 
         let mut size_vec = Vec::new();
         for items in self.items.iter().as_slice() {
-            let size = &items.len();
+            let size = &display_command(items).len();
             size_vec.push(*size);
         }
 
@@ -606,6 +2715,8 @@ impl FuzzyHistorySelect<'_> {
             .min(visible_term_rows);
         // Variable used to determine if we need to scroll through the list.
         let mut starting_row = 0;
+        // The mode and search term the highlight regex was compiled for
+        let mut highlight: Option<(SearchMode, String, Option<Regex>)> = None;
 
         term.hide_cursor()?;
         // term.show_cursor()?;
@@ -613,7 +2724,19 @@ impl FuzzyHistorySelect<'_> {
         loop {
             render.clear()?;
             // println!("{:#?}", "loop");
-            render.fuzzy_select_prompt(self.prompt.as_str(), &search_term, position)?;
+            render.fuzzy_select_prompt(&self.status_prompt(sel), &search_term, position)?;
+
+            // Filters like `exit:0` aren't part of the command
+            let highlight_term = parse_query(&search_term, Ulid::new().timestamp_ms()).text;
+
+            let highlight_stale = match &highlight {
+                Some((mode, term, _)) => *mode != self.mode || *term != highlight_term,
+                None => true,
+            };
+            if highlight_stale {
+                highlight = Some((self.mode, highlight_term.clone(), self.mode.highlight_regex(&highlight_term)));
+            }
+            let highlight_regex = highlight.as_ref().and_then(|(_, _, highlight_regex)| highlight_regex.as_ref());
 
             for (idx, item) in self
                 .items
@@ -622,13 +2745,52 @@ impl FuzzyHistorySelect<'_> {
                 .skip(starting_row)
                 .take(visible_term_rows)
             {
-                render.fuzzy_select_prompt_item(
-                    item,
-                    Some(idx) == sel,
-                    self.highlight_matches,
-                    &matcher,
-                    &search_term,
-                )?;
+                let display_text = match self.durations.get(idx) {
+                    // Plain text, escape codes would be picked up by the
+                    // theme's fuzzy highlighting
+                    Some(&duration_ms) if self.show_durations && duration_ms != UNKNOWN_DURATION => {
+                        format!("{}  ({})", display_command(item), format_duration(duration_ms))
+                    }
+                    _ => display_command(item),
+                };
+
+                if self.mode == SearchMode::Fuzzy {
+                    render.fuzzy_select_prompt_item(
+                        &display_text,
+                        Some(idx) == sel,
+                        self.highlight_matches,
+                        &matcher,
+                        &highlight_term,
+                    )?;
+                } else {
+                    // The theme only knows how to highlight skim matches, so
+                    // other modes are styled here and the theme's highlighting
+                    // is turned off. Every part of the selected row gets the
+                    // active style, the bold match's reset code would end it.
+                    let item_style = if Some(idx) == sel {
+                        self.active_item_style.clone()
+                    } else {
+                        console::Style::new().for_stderr()
+                    };
+
+                    let display_text = match highlight_regex.and_then(|regex| highlight_range(regex, &display_text)) {
+                        Some((start, end)) if self.highlight_matches => format!(
+                            "{}{}{}",
+                            item_style.apply_to(&display_text[..start]),
+                            item_style.clone().bold().apply_to(&display_text[start..end]),
+                            item_style.apply_to(&display_text[end..])
+                        ),
+                        _ => display_text,
+                    };
+
+                    render.fuzzy_select_prompt_item(
+                        &display_text,
+                        Some(idx) == sel,
+                        false,
+                        &matcher,
+                        &highlight_term,
+                    )?;
+                }
             }
             term.flush()?;
 
@@ -660,6 +2822,10 @@ impl FuzzyHistorySelect<'_> {
                 }
                 (Key::ArrowDown | Key::Tab, _) if !self.items.is_empty() => {
                     // println!("{:#?}", "ArrowDown");
+                    if sel == Some(self.items.len() - 1) {
+                        self.load_next_page();
+                    }
+
                     sel = match sel {
                         None => Some(0),
                         Some(sel) => Some((sel as u64 + 1).rem(self.items.len() as u64) as usize),
@@ -700,6 +2866,29 @@ impl FuzzyHistorySelect<'_> {
                         term.flush()?;
                     }
                 }
+                // ctrl-s
+                (Key::Char('\x13'), _) => {
+                    self.mode = self.mode.next();
+                    self.set_items_from_search(search_term.clone());
+                    term.flush()?;
+
+                    sel = Some(0);
+                    starting_row = 0;
+                }
+                // ctrl-t
+                (Key::Char('\x14'), _) => {
+                    self.show_durations = !self.show_durations;
+                    term.flush()?;
+                }
+                // ctrl-f
+                (Key::Char('\x06'), _) => {
+                    self.scope = self.scope.next(!self.session.is_empty());
+                    self.set_items_from_search(search_term.clone());
+                    term.flush()?;
+
+                    sel = Some(0);
+                    starting_row = 0;
+                }
                 (Key::Char(chr), _) if !chr.is_ascii_control() => {
                     // println!("char: {:#?}", chr);
 
@@ -778,6 +2967,31 @@ impl FuzzyHistorySelect<'_> {
     }
 }
 
+/// `850ms`, `2.3s`, `4m 5s` or `1h 2m`.
+fn format_duration(duration_ms: u64) -> String {
+    let seconds = duration_ms / 1000;
+
+    if duration_ms < 1000 {
+        format!("{}ms", duration_ms)
+    } else if seconds < 60 {
+        format!("{:.1}s", duration_ms as f64 / 1000.0)
+    } else if seconds < 60 * 60 {
+        format!("{}m {}s", seconds / 60, seconds % 60)
+    } else {
+        format!("{}h {}m", seconds / (60 * 60), seconds / 60 % 60)
+    }
+}
+
+/// Flattens multi-line commands onto a single row for the picker, each newline
+/// is shown as `↵` so heredocs and loops stay readable.
+fn display_command(command: &str) -> String {
+    command
+        .lines()
+        .map(|line| line.trim_end())
+        .collect::<Vec<&str>>()
+        .join(" ↵ ")
+}
+
 impl<'a> FuzzyHistorySelect<'a> {
     /// Same as `new` but with a specific theme.
     pub fn with_theme(theme: &'a dyn Theme) -> Self {
@@ -791,6 +3005,15 @@ impl<'a> FuzzyHistorySelect<'a> {
             max_length: None,
             theme,
             initial_text: "".into(),
+            mode: SearchMode::Fuzzy,
+            scope: SearchScope::Global,
+            session: "".into(),
+            limit: None,
+            query: "".into(),
+            total: 0,
+            durations: vec![],
+            show_durations: false,
+            active_item_style: console::Style::new().for_stderr(),
         }
     }
 }
@@ -1103,3 +3326,724 @@ impl<'a> TermThemeRenderer<'a> {
 //         );
 //     }
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW_MS: u64 = 1_700_000_000_000;
+    const HOUR_MS: u64 = 60 * 60 * 1000;
+
+    fn entry(command: &str, directory: &str, exit_code: u64, timestamp: u64) -> HistoryEntry {
+        HistoryEntry {
+            directory: directory.to_string(),
+            command: command.to_string(),
+            exit_code,
+            timestamp,
+            ..Default::default()
+        }
+    }
+
+    fn fixture_index(entries: Vec<(HistoryEntry, u64)>) -> Index {
+        let schema = build_schema();
+        let index = Index::builder()
+            .schema(schema.clone())
+            .settings(index_settings())
+            .create_in_ram()
+            .unwrap();
+
+        add_entries(&index, entries);
+        index
+    }
+
+    /// Indexes `entries` in one commit, like an import does.
+    fn add_entries(index: &Index, entries: Vec<(HistoryEntry, u64)>) {
+        let schema = index.schema();
+        let searcher = build_searcher(index);
+        let mut index_writer = index.writer_with_num_threads(1, 30_000_000).unwrap();
+
+        for (entry, runs) in entries {
+            index_command(entry, CommandUses::runs(runs), &schema, &searcher, &index_writer);
+        }
+
+        index_writer.commit().unwrap();
+    }
+
+    fn context(current_dir: &str) -> SearchContext {
+        SearchContext {
+            current_dir: current_dir.to_string(),
+            current_ms: NOW_MS,
+            session: "".to_string(),
+            repository: "".to_string(),
+            half_life_ms: 14 * DAY_MS,
+            instant_failure_ms: 50,
+            weights: RankingWeights::default(),
+        }
+    }
+
+    fn search(index: &Index, text: &str, current_dir: &str) -> Vec<String> {
+        search_index(index, text.to_string(), SearchMode::Fuzzy, SearchScope::Global, &context(current_dir), 0, 100).commands
+    }
+
+    #[test]
+    fn recent_successful_command_ranks_above_old_failing_one() {
+        let index = fixture_index(vec![
+            (entry("git stash list", "/tmp", 1, NOW_MS - 20 * DAY_MS), 1),
+            (entry("git status", "/tmp", 0, NOW_MS - HOUR_MS), 1),
+        ]);
+
+        let results = search(&index, "git st", "/home");
+
+        assert_eq!(results, vec!["git status", "git stash list"]);
+    }
+
+    #[test]
+    fn successful_command_ranks_above_failing_one_from_the_same_time() {
+        let index = fixture_index(vec![
+            (entry("cargo build", "/tmp", 0, NOW_MS - HOUR_MS), 1),
+            (entry("cargo bench", "/tmp", 101, NOW_MS - HOUR_MS), 1),
+        ]);
+
+        let results = search(&index, "cargo b", "/home");
+
+        assert_eq!(results, vec!["cargo build", "cargo bench"]);
+    }
+
+    #[test]
+    fn frequently_run_command_ranks_above_rarely_run_one() {
+        let index = fixture_index(vec![
+            (entry("make test", "/tmp", 0, NOW_MS - DAY_MS), 80),
+            (entry("make tags", "/tmp", 0, NOW_MS - DAY_MS), 1),
+        ]);
+
+        let results = search(&index, "make t", "/home");
+
+        assert_eq!(results, vec!["make test", "make tags"]);
+    }
+
+    #[test]
+    fn older_command_from_the_same_segment_is_not_boosted_by_the_newest() {
+        let index = fixture_index(vec![
+            (entry("ls -la", "/tmp", 0, NOW_MS - HOUR_MS), 1),
+            (entry("ls -lh", "/tmp", 0, NOW_MS - 25 * DAY_MS), 1),
+            (entry("ls -l", "/tmp", 0, NOW_MS - 10 * DAY_MS), 1),
+        ]);
+
+        let results = search(&index, "ls", "/home");
+
+        assert_eq!(results, vec!["ls -la", "ls -l", "ls -lh"]);
+    }
+
+    #[test]
+    fn closer_fuzzy_match_ranks_first() {
+        let index = fixture_index(vec![
+            (entry("git stash list --format='%gd %s'", "/tmp", 0, NOW_MS - HOUR_MS), 1),
+            (entry("git status", "/tmp", 0, NOW_MS - HOUR_MS), 1),
+            (entry("gpg --list-secret-keys --keyid-format LONG", "/tmp", 0, NOW_MS - HOUR_MS), 1),
+        ]);
+
+        let results = search(&index, "gst", "/home");
+
+        assert_eq!(results[0], "git status");
+    }
+
+    #[test]
+    fn command_length_only_breaks_exact_ties() {
+        let index = fixture_index(vec![
+            (entry("git stash list", "/tmp", 0, NOW_MS - HOUR_MS + 60_000), 1),
+            (entry("git status", "/tmp", 0, NOW_MS - HOUR_MS), 1),
+        ]);
+
+        assert_eq!(search(&index, "gst", "/home"), vec!["git stash list", "git status"]);
+    }
+
+    #[test]
+    fn rerunning_a_command_keeps_a_single_document() {
+        let index = fixture_index(vec![
+            (entry("cargo run", "/tmp", 0, NOW_MS - DAY_MS), 1),
+            (entry("cargo run", "/tmp", 0, NOW_MS - HOUR_MS), 1),
+        ]);
+
+        let results = search(&index, "cargo", "/tmp");
+
+        assert_eq!(results, vec!["cargo run"]);
+    }
+
+    #[test]
+    fn fuzzy_search_matches_regex_metacharacters_literally() {
+        let index = fixture_index(vec![
+            (entry("echo $(date)", "/tmp", 0, NOW_MS - HOUR_MS), 1),
+            (entry("ls *.rs", "/tmp", 0, NOW_MS - HOUR_MS), 1),
+            (entry("echo date", "/tmp", 0, NOW_MS - HOUR_MS), 1),
+        ]);
+
+        assert_eq!(search(&index, "$(", "/home"), vec!["echo $(date)"]);
+        assert_eq!(search(&index, "*.rs", "/home"), vec!["ls *.rs"]);
+        assert_eq!(search(&index, "a|b", "/home"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn regex_mode_uses_the_text_as_a_pattern() {
+        let index = fixture_index(vec![
+            (entry("cargo build", "/tmp", 0, NOW_MS - HOUR_MS), 1),
+            (entry("cargo test", "/tmp", 0, NOW_MS - HOUR_MS), 1),
+            (entry("cargo check", "/tmp", 0, NOW_MS - HOUR_MS), 1),
+        ]);
+
+        let search_regex = |text: &str| {
+            search_index(&index, text.to_string(), SearchMode::Regex, SearchScope::Global, &context("/home"), 0, 100).commands
+        };
+
+        let mut results = search_regex("cargo (build|test)");
+        results.sort();
+
+        assert_eq!(results, vec!["cargo build", "cargo test"]);
+        assert_eq!(search_regex("cargo ("), Vec::<String>::new());
+    }
+
+    #[test]
+    fn query_filters_are_split_from_the_text() {
+        let parsed_query = parse_query("git push -u exit:!0 not:force host:build01 http://example.com", NOW_MS);
+
+        assert_eq!(parsed_query.text, "git push -u http://example.com");
+        assert_eq!(
+            parsed_query.filters,
+            vec![
+                QueryFilter::NotExitCode(0),
+                QueryFilter::Exclude("force".to_string()),
+                QueryFilter::Host("build01".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn query_times_parse_dates_and_relative_ages() {
+        assert_eq!(parse_query_time("1970-01-02", NOW_MS), Some(DAY_MS));
+        assert_eq!(parse_query_time("2023-11-14", NOW_MS), Some(NOW_MS - NOW_MS % DAY_MS));
+        assert_eq!(parse_query_time("today", NOW_MS), Some(NOW_MS - NOW_MS % DAY_MS));
+        assert_eq!(parse_query_time("yesterday", NOW_MS), Some(NOW_MS - NOW_MS % DAY_MS - DAY_MS));
+        assert_eq!(parse_query_time("2d", NOW_MS), Some(NOW_MS - 2 * DAY_MS));
+        assert_eq!(parse_query_time("2023-13-01", NOW_MS), None);
+        assert_eq!(parse_query_time("soon", NOW_MS), None);
+    }
+
+    #[test]
+    fn query_filters_narrow_results() {
+        let index = fixture_index(vec![
+            (entry("make build", "/src/api", 0, NOW_MS - HOUR_MS), 1),
+            (entry("make test", "/src/api/web", 2, NOW_MS - 2 * HOUR_MS), 1),
+            (entry("make deploy", "/src/app", 0, NOW_MS - 3 * DAY_MS), 1),
+            (entry("make lint", "/src/app", UNKNOWN_EXIT_CODE, NOW_MS - HOUR_MS), 1),
+        ]);
+
+        assert_eq!(search(&index, "make exit:!0", "/home"), vec!["make test"]);
+        assert_eq!(search(&index, "make dir:/src/api exit:0", "/home"), vec!["make build"]);
+        assert_eq!(search(&index, "make dir:/src/app before:1d", "/home"), vec!["make deploy"]);
+
+        let mut results = search(&index, "make after:1d not:lint", "/home");
+        results.sort();
+        assert_eq!(results, vec!["make build", "make test"]);
+    }
+
+    #[test]
+    fn flags_in_the_search_text_are_matched() {
+        let index = fixture_index(vec![
+            (entry("git commit -m \"msg\"", "/src", 0, NOW_MS - HOUR_MS), 1),
+            (entry("ls -la", "/src", 0, NOW_MS - HOUR_MS), 1),
+        ]);
+
+        assert_eq!(search(&index, "git commit -m", "/src"), vec!["git commit -m \"msg\""]);
+        assert_eq!(search(&index, "ls -la", "/src"), vec!["ls -la"]);
+    }
+
+    #[test]
+    fn search_modes_match_commands_differently() {
+        let index = fixture_index(vec![
+            (entry("git status", "/tmp", 0, NOW_MS - HOUR_MS), 1),
+            (entry("echo digits", "/tmp", 0, NOW_MS - 2 * HOUR_MS), 1),
+            (entry("gitk --all", "/tmp", 0, NOW_MS - 3 * HOUR_MS), 1),
+            (entry("cat <<EOF\ngit log\nEOF", "/tmp", 0, NOW_MS - 4 * HOUR_MS), 1),
+        ]);
+
+        let search_mode = |text: &str, mode: SearchMode| {
+            search_index(&index, text.to_string(), mode, SearchScope::Global, &context("/home"), 0, 100).commands
+        };
+
+        assert_eq!(search_mode("git", SearchMode::Prefix), vec!["git status", "gitk --all"]);
+        assert_eq!(
+            search_mode("git", SearchMode::Substring),
+            vec!["git status", "echo digits", "gitk --all", "cat <<EOF\ngit log\nEOF"]
+        );
+        assert_eq!(search_mode("git", SearchMode::Word), vec!["git status", "cat <<EOF\ngit log\nEOF"]);
+    }
+
+    #[test]
+    fn search_modes_cycle_and_highlight_their_matches() {
+        assert_eq!(SearchMode::Regex.next(), SearchMode::Fuzzy);
+        assert_eq!(SearchMode::from_name("word"), Some(SearchMode::Word));
+
+        let highlight = |mode: SearchMode, text: &str, search_term: &str| {
+            highlight_range(&mode.highlight_regex(search_term).unwrap(), text)
+        };
+
+        assert_eq!(highlight(SearchMode::Prefix, "git status", "git"), Some((0, 3)));
+        assert_eq!(highlight(SearchMode::Prefix, "echo git", "git"), None);
+        assert_eq!(highlight(SearchMode::Word, "digit git", "git"), Some((6, 9)));
+        assert_eq!(highlight(SearchMode::Regex, "cargo test", "t[a-z]+"), Some((6, 10)));
+        assert!(SearchMode::Fuzzy.highlight_regex("git").is_none());
+    }
+
+    #[test]
+    fn search_scopes_limit_where_commands_were_run() {
+        let mut in_session = entry("make build", "/src/api", 0, NOW_MS - HOUR_MS);
+        in_session.session = "01HSESSION".to_string();
+
+        let index = fixture_index(vec![
+            (in_session, 1),
+            (entry("make test", "/src/api/web", 0, NOW_MS - 2 * HOUR_MS), 1),
+            (entry("make deploy", "/src/app", 0, NOW_MS - 3 * HOUR_MS), 1),
+        ]);
+
+        let mut context = context("/src/api");
+        context.session = "01HSESSION".to_string();
+
+        let search_scope = |scope: SearchScope| {
+            search_index(&index, "make".to_string(), SearchMode::Fuzzy, scope, &context, 0, 100).commands
+        };
+
+        assert_eq!(search_scope(SearchScope::Global), vec!["make build", "make test", "make deploy"]);
+        assert_eq!(search_scope(SearchScope::Directory), vec!["make build"]);
+        assert_eq!(search_scope(SearchScope::Subtree), vec!["make build", "make test"]);
+        assert_eq!(search_scope(SearchScope::Session), vec!["make build"]);
+        assert_eq!(SearchScope::Repository.next(false), SearchScope::Global);
+    }
+
+    #[test]
+    fn search_pages_through_results_and_counts_them_all() {
+        let index = fixture_index(vec![
+            (entry("make build", "/src", 0, NOW_MS - HOUR_MS), 1),
+            (entry("make test", "/src", 0, NOW_MS - 2 * HOUR_MS), 1),
+            (entry("make deploy", "/src", 0, NOW_MS - 3 * HOUR_MS), 1),
+            (entry("cargo build", "/src", 0, NOW_MS - 4 * HOUR_MS), 1),
+        ]);
+
+        let search_page = |offset: usize, limit: usize| {
+            search_index(&index, "make".to_string(), SearchMode::Fuzzy, SearchScope::Global, &context("/src"), offset, limit)
+        };
+
+        let first_page = search_page(0, 2);
+        assert_eq!(first_page.commands, vec!["make build", "make test"]);
+        assert_eq!(first_page.total, 3);
+
+        let last_page = search_page(2, 2);
+        assert_eq!(last_page.commands, vec!["make deploy"]);
+        assert_eq!(last_page.total, 3);
+
+        assert_eq!(search_page(0, 0).commands, Vec::<String>::new());
+    }
+
+    #[test]
+    fn nearby_directories_are_boosted_by_distance() {
+        let index = fixture_index(vec![
+            (entry("make other", "/other", 0, NOW_MS - HOUR_MS), 1),
+            (entry("make root", "/src", 0, NOW_MS - HOUR_MS), 1),
+            (entry("make app", "/src/app", 0, NOW_MS - HOUR_MS), 1),
+            (entry("make web", "/src/app/web", 0, NOW_MS - HOUR_MS), 1),
+        ]);
+
+        assert_eq!(search(&index, "make", "/src/app/web"), vec!["make web", "make app", "make root", "make other"]);
+        assert_eq!(search(&index, "make", "/src")[..3], ["make root", "make app", "make web"]);
+    }
+
+    #[test]
+    fn directory_ancestors_and_proximity() {
+        assert_eq!(directory_ancestors("/src/app/"), vec!["/src/app", "/src", "/"]);
+        assert_eq!(directory_ancestors(""), Vec::<String>::new());
+
+        assert_eq!(directory_proximity("/src/app", "/src/app"), 1.0);
+        assert_eq!(directory_proximity("/src/app", "/src"), 0.5);
+        assert_eq!(directory_proximity("/src", "/src/app/web"), 1.0 / 3.0);
+        assert_eq!(directory_proximity("/src/app", "/src/application"), 0.0);
+    }
+
+    #[test]
+    fn imports_without_a_directory_share_identity_with_live_commands() {
+        let index = fixture_index(vec![(entry("make", UNKNOWN_DIRECTORY, 0, NOW_MS - DAY_MS), 3)]);
+        let indexed_entries = || {
+            let searcher = build_searcher(&index);
+
+            find_documents(&searcher, &AllQuery)
+                .iter()
+                .map(|doc| {
+                    let entry = HistoryEntry::from_document(doc, &index.schema());
+                    let run_count = stored_u64(doc, index.schema().get_field("run_count").unwrap());
+
+                    (entry.directory, entry.timestamp, run_count)
+                })
+                .collect::<Vec<(String, u64, u64)>>()
+        };
+
+        assert_eq!(indexed_entries(), vec![(UNKNOWN_DIRECTORY.to_string(), NOW_MS - DAY_MS, 3)]);
+
+        // The next live run takes over the imported counters
+        add_entries(&index, vec![(entry("make", "/src", 0, NOW_MS - HOUR_MS), 1)]);
+        assert_eq!(indexed_entries(), vec![("/src".to_string(), NOW_MS - HOUR_MS, 4)]);
+
+        // An older import counts towards the live copy without replacing it
+        add_entries(&index, vec![(entry("make", UNKNOWN_DIRECTORY, 0, NOW_MS - 2 * DAY_MS), 2)]);
+        assert_eq!(indexed_entries(), vec![("/src".to_string(), NOW_MS - HOUR_MS, 6)]);
+    }
+
+    #[test]
+    fn instant_failures_rank_below_slow_ones() {
+        let mut typo = entry("make tset", "/src", 127, NOW_MS - HOUR_MS);
+        typo.duration_ms = 5;
+        let mut failing_test = entry("make test", "/src", 2, NOW_MS - HOUR_MS);
+        failing_test.duration_ms = 40_000;
+
+        let index = fixture_index(vec![(typo, 1), (failing_test, 1)]);
+
+        assert_eq!(search(&index, "make", "/src"), vec!["make test", "make tset"]);
+    }
+
+    #[test]
+    fn durations_are_formatted_for_the_picker() {
+        assert_eq!(format_duration(850), "850ms");
+        assert_eq!(format_duration(2_340), "2.3s");
+        assert_eq!(format_duration(245_000), "4m 5s");
+        assert_eq!(format_duration(3_720_000), "1h 2m");
+    }
+
+    #[test]
+    fn shell_context_is_stored_with_each_command() {
+        let shell_entry = HistoryEntry {
+            hostname: "build01".to_string(),
+            username: "ci".to_string(),
+            session: "01HSESSION".to_string(),
+            shell: "zsh".to_string(),
+            tty: "/dev/ttys003".to_string(),
+            pid: 4242,
+            ..entry("make", "/src", 0, NOW_MS - HOUR_MS)
+        };
+        let index = fixture_index(vec![(shell_entry, 1)]);
+
+        let searcher = build_searcher(&index);
+        let docs = find_documents(&searcher, &AllQuery);
+        let stored_entry = HistoryEntry::from_document(&docs[0], &index.schema());
+
+        assert_eq!(
+            (
+                stored_entry.hostname.as_str(),
+                stored_entry.username.as_str(),
+                stored_entry.session.as_str(),
+                stored_entry.shell.as_str(),
+                stored_entry.tty.as_str(),
+                stored_entry.pid,
+            ),
+            ("build01", "ci", "01HSESSION", "zsh", "/dev/ttys003", 4242)
+        );
+    }
+
+    #[test]
+    fn remote_urls_are_normalized_to_host_and_path() {
+        for url in [
+            "git@github.com:o/r.git",
+            "https://github.com/o/r",
+            "https://github.com/o/r.git",
+            "ssh://git@github.com:22/o/r.git",
+            "https://user@GitHub.com/o/r/",
+        ] {
+            assert_eq!(normalize_remote_url(url), "github.com/o/r", "{}", url);
+        }
+
+        assert_eq!(normalize_remote_url("/srv/git/r.git"), "/srv/git/r");
+    }
+
+    #[test]
+    fn commands_from_the_same_repository_are_boosted_at_any_path() {
+        let repository_entry = |command: &str, directory: &str, repository: &str| HistoryEntry {
+            git: GitContext {
+                root: directory.to_string(),
+                repository: repository.to_string(),
+                ..Default::default()
+            },
+            ..entry(command, directory, 0, NOW_MS - HOUR_MS)
+        };
+
+        let index = fixture_index(vec![
+            (repository_entry("make other", "/src/other", "example.com/other"), 1),
+            (repository_entry("make clone", "/src/app", "example.com/app"), 1),
+        ]);
+
+        let mut context = context("/tmp/app-review");
+        context.repository = "example.com/app".to_string();
+
+        let search_repository = |scope: SearchScope| {
+            search_index(&index, "make".to_string(), SearchMode::Fuzzy, scope, &context, 0, 100).commands
+        };
+
+        assert_eq!(search_repository(SearchScope::Global), vec!["make clone", "make other"]);
+        assert_eq!(search_repository(SearchScope::Repository), vec!["make clone"]);
+    }
+
+    #[test]
+    fn git_context_is_read_from_the_git_directory() {
+        let root = std::env::temp_dir().join(format!("fzh-git-{}", Ulid::new()));
+        let git_dir = root.join(".git");
+        let worktree = root.join("worktree");
+        fs::create_dir_all(root.join("web/src")).unwrap();
+        fs::create_dir_all(git_dir.join("worktrees/feature")).unwrap();
+        fs::create_dir_all(&worktree).unwrap();
+
+        fs::write(git_dir.join("HEAD"), "ref: refs/heads/main\n").unwrap();
+        fs::write(
+            git_dir.join("config"),
+            "[core]\n\tbare = false\n[remote \"origin\"]\n\turl = git@example.com:app.git\n",
+        )
+        .unwrap();
+        fs::write(git_dir.join("worktrees/feature/HEAD"), "ref: refs/heads/feature/login\n").unwrap();
+        fs::write(git_dir.join("worktrees/feature/commondir"), "../..\n").unwrap();
+        fs::write(worktree.join(".git"), format!("gitdir: {}\n", git_dir.join("worktrees/feature").display())).unwrap();
+
+        let context = git_context(&root.join("web/src")).unwrap();
+        assert_eq!(context.root, root.to_str().unwrap());
+        assert_eq!(context.branch, "main");
+        assert_eq!(context.relative_path, "web/src");
+        assert_eq!(context.repository, "example.com/app");
+
+        let worktree_context = git_context(&worktree).unwrap();
+        assert_eq!(worktree_context.branch, "feature/login");
+        assert_eq!(worktree_context.relative_path, "");
+        assert_eq!(worktree_context.repository, "example.com/app");
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn selections_are_counted_separately_from_runs() {
+        let index = fixture_index(vec![
+            (entry("make build", "/src", 0, NOW_MS - HOUR_MS), 5),
+            (entry("make build", "/other", 0, NOW_MS - 2 * HOUR_MS), 1),
+            (entry("make test", "/src", 0, NOW_MS - HOUR_MS), 5),
+        ]);
+
+        record_selection(&index, "make test", "/src");
+        record_selection(&index, "make build", "/other");
+
+        let counts = |command: &str, directory: &str| {
+            let schema = index.schema();
+            let searcher = build_searcher(&index);
+            let id_term = tantivy::Term::from_field_u64(schema.get_field("id").unwrap(), command_id(directory, command));
+            let doc = &find_documents(&searcher, &TermQuery::new(id_term, IndexRecordOption::Basic))[0];
+
+            (
+                stored_u64(doc, schema.get_field("run_count").unwrap()),
+                stored_u64(doc, schema.get_field("selected_count").unwrap()),
+            )
+        };
+
+        assert_eq!(counts("make test", "/src"), (5, 1));
+        assert_eq!(counts("make build", "/src"), (5, 0));
+        assert_eq!(counts("make build", "/other"), (1, 1));
+        assert_eq!(search(&index, "make", "/src")[0], "make test");
+    }
+
+    #[test]
+    fn frecency_keeps_heavily_used_commands_and_fades_one_offs() {
+        let index = fixture_index(vec![
+            (entry("deploy --once", "/tmp", 0, NOW_MS - 365 * DAY_MS), 1),
+            (entry("deploy --prod", "/tmp", 0, NOW_MS - 120 * DAY_MS), 500),
+            (entry("deploy --dry-run", "/tmp", 0, NOW_MS - 30 * DAY_MS), 1),
+        ]);
+
+        assert_eq!(search(&index, "deploy", "/home"), vec!["deploy --prod", "deploy --dry-run", "deploy --once"]);
+    }
+
+    #[test]
+    fn frecency_decays_by_half_every_half_life() {
+        assert_eq!(decay_frecency(8.0, 0, 14 * DAY_MS, 14 * DAY_MS), 4.0);
+        assert_eq!(decay_frecency(8.0, 0, 42 * DAY_MS, 14 * DAY_MS), 1.0);
+        assert_eq!(decay_frecency(1.0, 14 * DAY_MS, 0, 14 * DAY_MS), 2.0);
+    }
+
+    #[test]
+    fn config_overrides_some_weights_and_keeps_the_rest() {
+        let config = parse_config("half_life_days = 30\n\n[weights]\nfuzzy = 0.5\nsession = 3\n").unwrap();
+
+        assert_eq!(config.half_life_days, 30.0);
+        assert_eq!(config.weights.fuzzy, 0.5);
+        assert_eq!(config.weights.session, 3.0);
+        assert_eq!(config.weights.recency, RankingWeights::default().recency);
+        assert_eq!(config.instant_failure_ms, 50);
+
+        assert!(parse_config("[weights]\nfuzzyness = 1.0\n").is_err());
+        assert!(parse_config("half_life_days = 0\n").is_err());
+    }
+
+    #[test]
+    fn ranking_weights_change_the_order() {
+        let index = fixture_index(vec![
+            (entry("make build", "/src", 0, NOW_MS - HOUR_MS), 1),
+            (entry("make test", "/other", 0, NOW_MS - 10 * DAY_MS), 1),
+        ]);
+
+        let mut context = context("/other");
+        assert_eq!(
+            search_index(&index, "make".to_string(), SearchMode::Fuzzy, SearchScope::Global, &context, 0, 100).commands,
+            vec!["make test", "make build"]
+        );
+
+        context.weights.directory = 0.0;
+        assert_eq!(
+            search_index(&index, "make".to_string(), SearchMode::Fuzzy, SearchScope::Global, &context, 0, 100).commands,
+            vec!["make build", "make test"]
+        );
+    }
+
+    #[test]
+    fn fish_paths_are_kept_apart_from_the_directory() {
+        let entries = fish_history_entries("- cmd: vim /etc/hosts\n  when: 1700000000\n  paths:\n    - /etc/hosts\n");
+
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].directory, UNKNOWN_DIRECTORY);
+        assert_eq!(entries[0].timestamp, NOW_MS);
+        assert_eq!(entries[0].paths, vec!["/etc/hosts"]);
+
+        let index = fixture_index(entries.into_iter().map(|entry| (entry, 1)).collect());
+        let searcher = build_searcher(&index);
+        let docs = find_documents(&searcher, &AllQuery);
+        assert_eq!(HistoryEntry::from_document(&docs[0], &index.schema()).paths, vec!["/etc/hosts"]);
+    }
+
+    #[test]
+    fn indexes_with_an_older_schema_are_migrated() {
+        // The schema before fzh kept exit codes, durations or context
+        let mut old_schema_builder = Schema::builder();
+        let id_field = old_schema_builder.add_u64_field("id", FAST | INDEXED | STORED);
+        let timestamp_field = old_schema_builder.add_u64_field("timestamp", FAST | INDEXED | STORED);
+        let times_selected_field = old_schema_builder.add_u64_field("times_selected", FAST | INDEXED | STORED);
+        let directory_field = old_schema_builder.add_text_field("directory", STRING | STORED);
+        let command_field = old_schema_builder.add_text_field("command", STRING | STORED);
+        let old_index = Index::create_in_ram(old_schema_builder.build());
+
+        let mut old_writer = old_index.writer_with_num_threads(1, 30_000_000).unwrap();
+        // Older versions appended a copy on every run
+        for (directory, command, timestamp, times_selected) in [
+            ("/src", "make", NOW_MS - DAY_MS, 1),
+            ("/src", "make", NOW_MS - HOUR_MS, 2),
+            // Imports had no directory, and were kept apart from live runs
+            ("", "ls", NOW_MS - 2 * DAY_MS, 3),
+            ("/src", "ls", NOW_MS - DAY_MS, 1),
+            ("", "pwd", NOW_MS - 2 * DAY_MS, 1),
+        ] {
+            let mut doc = Document::default();
+            doc.add_u64(id_field, command_id(directory, command));
+            doc.add_u64(timestamp_field, timestamp);
+            doc.add_u64(times_selected_field, times_selected);
+            doc.add_text(directory_field, directory);
+            doc.add_text(command_field, command);
+            old_writer.add_document(doc).unwrap();
+        }
+        old_writer.commit().unwrap();
+
+        let mut entries = migrated_entries(&old_index);
+        entries.sort_by_key(|(entry, _)| entry.command.clone());
+
+        assert_eq!(entries.len(), 3);
+
+        let (ls, ls_uses) = &entries[0];
+        assert_eq!((ls.directory.as_str(), ls.timestamp, ls_uses.runs), ("/src", NOW_MS - DAY_MS, 4));
+
+        let (make, make_uses) = &entries[1];
+        assert_eq!((make.directory.as_str(), make.timestamp, make_uses.runs), ("/src", NOW_MS - HOUR_MS, 2));
+        assert_eq!(make.duration_ms, UNKNOWN_DURATION);
+
+        let (pwd, pwd_uses) = &entries[2];
+        assert_eq!((pwd.directory.as_str(), pwd.exit_code, pwd_uses.runs), (UNKNOWN_DIRECTORY, UNKNOWN_EXIT_CODE, 1));
+
+        let index = fixture_index(vec![]);
+        reindex_entries(&index, entries);
+
+        assert_eq!(search(&index, "make", "/src"), vec!["make"]);
+        assert_eq!(search(&index, "ls", "/src"), vec!["ls"]);
+    }
+
+    #[test]
+    fn imports_fold_commands_without_a_directory_into_ones_with_one() {
+        let index = fixture_index(vec![]);
+
+        import_entries(
+            &index,
+            vec![
+                entry("ls", UNKNOWN_DIRECTORY, UNKNOWN_EXIT_CODE, NOW_MS - 2 * DAY_MS),
+                entry("ls", "/src", 0, NOW_MS - DAY_MS),
+                entry("ls", UNKNOWN_DIRECTORY, UNKNOWN_EXIT_CODE, NOW_MS - HOUR_MS),
+            ],
+        );
+
+        let searcher = build_searcher(&index);
+        let docs = find_documents(&searcher, &AllQuery);
+        assert_eq!(docs.len(), 1);
+
+        let ls = HistoryEntry::from_document(&docs[0], &index.schema());
+        assert_eq!((ls.directory.as_str(), ls.timestamp), ("/src", NOW_MS - HOUR_MS));
+        assert_eq!(CommandUses::from_document(&docs[0], &index.schema()).runs, 3);
+    }
+
+    #[test]
+    fn unknown_values_are_exported_as_missing() {
+        let schema = build_schema();
+        let field = |name: &str| schema.get_field(name).unwrap();
+
+        let mut doc = Document::default();
+        doc.add_u64(field("exit_code"), UNKNOWN_EXIT_CODE);
+        doc.add_u64(field("duration_ms"), UNKNOWN_DURATION);
+        doc.add_text(field("directory"), UNKNOWN_DIRECTORY);
+        doc.add_text(field("command"), "ls");
+
+        assert_eq!(export_value(&doc, field("exit_code"), "exit_code"), serde_json::Value::Null);
+        assert_eq!(export_value(&doc, field("duration_ms"), "duration_ms"), serde_json::Value::Null);
+        assert_eq!(export_value(&doc, field("directory"), "directory"), serde_json::Value::Null);
+        assert_eq!(export_value(&doc, field("command"), "command"), serde_json::Value::from("ls"));
+
+        let mut known_doc = Document::default();
+        known_doc.add_u64(field("exit_code"), 0);
+        assert_eq!(export_value(&known_doc, field("exit_code"), "exit_code"), serde_json::Value::from(0u64));
+    }
+
+    #[test]
+    fn older_imports_count_towards_the_live_run_once() {
+        let mut live_entry = entry("cargo test", "/src", 0, NOW_MS);
+        live_entry.duration_ms = 1200;
+        live_entry.shell = "zsh".to_string();
+        let index = fixture_index(vec![(live_entry, 1)]);
+        let schema = index.schema();
+
+        let indexed_doc = || {
+            let searcher = build_searcher(&index);
+            let command_term = tantivy::Term::from_field_text(schema.get_field("command").unwrap(), "cargo test");
+            let mut docs = find_documents(&searcher, &TermQuery::new(command_term, IndexRecordOption::Basic));
+            assert_eq!(docs.len(), 1);
+            docs.pop().unwrap()
+        };
+
+        // Fish and zsh history from before the hooks were sourced
+        let history = || {
+            vec![
+                entry("cargo test", UNKNOWN_DIRECTORY, UNKNOWN_EXIT_CODE, NOW_MS - 2 * DAY_MS),
+                entry("cargo test", "/src", 101, NOW_MS - DAY_MS),
+            ]
+        };
+
+        let mut import_mark = None;
+        import_entries(&index, skip_imported_entries(history(), &mut import_mark));
+        import_entries(&index, skip_imported_entries(history(), &mut import_mark));
+        assert_eq!(import_mark, Some(NOW_MS - DAY_MS));
+
+        // Both older runs are counted, once, and the live run's details are kept
+        let latest = HistoryEntry::from_document(&indexed_doc(), &schema);
+        assert_eq!(CommandUses::from_document(&indexed_doc(), &schema).runs, 3);
+        assert_eq!((latest.timestamp, latest.exit_code, latest.duration_ms), (NOW_MS, 0, 1200));
+        assert_eq!(latest.shell, "zsh");
+    }
+}